@@ -1 +1,266 @@
-pub mod x86;
+//! Atomic primitives used by the queues.
+//!
+//! Single word operations use `std::sync::atomic` on every target. The 16 byte
+//! compare-and-swap that ring nodes need is `CMPXCHG16B` on x86_64 CPUs that support it,
+//! and is emulated with a table of spinlocks everywhere else, or when the `emulated-cas`
+//! feature is on.
+//!
+//! The backend is picked once, the first time it's needed, and can't change afterwards
+//! since the two backends don't exclude each other. Setting the environment variable
+//! `CONCURRENT_QUEUE_CAS=emulated` forces the emulation, and so does calling
+//! `select_cas_backend` before any queue is used.
+//!
+//! With the `model` feature, every operation is a scheduling point of the model checker.
+
+use std::env;
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicU8, Ordering};
+
+#[cfg(all(target_arch = "x86_64", not(feature = "emulated-cas")))]
+mod x86;
+mod striped;
+
+#[cfg(feature = "model")]
+use model::yield_point;
+
+#[cfg(not(feature = "model"))]
+#[inline(always)]
+fn yield_point() {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CasBackend {
+    Native,   // CMPXCHG16B
+    Emulated, // striped spinlocks
+}
+
+const UNDECIDED: u8 = 0;
+const NATIVE: u8 = 1;
+const EMULATED: u8 = 2;
+
+static BACKEND: AtomicU8 = AtomicU8::new(UNDECIDED);
+
+#[cfg(all(target_arch = "x86_64", not(feature = "emulated-cas")))]
+fn native_supported() -> bool {
+    is_x86_feature_detected!("cmpxchg16b")
+}
+
+#[cfg(not(all(target_arch = "x86_64", not(feature = "emulated-cas"))))]
+fn native_supported() -> bool {
+    false
+}
+
+fn detect_backend() -> u8 {
+    let requested = env::var("CONCURRENT_QUEUE_CAS").ok();
+    if requested.as_ref().map(|s| &s[..]) != Some("emulated") && native_supported() {
+        NATIVE
+    } else {
+        EMULATED
+    }
+}
+
+fn backend() -> u8 {
+    let backend = BACKEND.load(Ordering::Relaxed);
+    if backend != UNDECIDED {
+        return backend;
+    }
+
+    // whoever decides first wins, so everybody ends up using the same backend
+    match BACKEND.compare_exchange(UNDECIDED, detect_backend(), Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_)        => BACKEND.load(Ordering::SeqCst),
+        Err(decided) => decided,
+    }
+}
+
+/// The backend used for the 16 byte compare-and-swap. Picks one if none has been picked yet.
+pub fn cas_backend() -> CasBackend {
+    if backend() == NATIVE { CasBackend::Native } else { CasBackend::Emulated }
+}
+
+/// Pick the backend used for the 16 byte compare-and-swap. This only works before any
+/// queue has been used, or if the requested backend is already in use. Otherwise, or if
+/// the CPU doesn't support `CasBackend::Native`, the backend in use is returned as error.
+pub fn select_cas_backend(requested: CasBackend) -> Result<(), CasBackend> {
+    let requested = match requested {
+        CasBackend::Native if native_supported() => NATIVE,
+        CasBackend::Native => return Err(cas_backend()),
+        CasBackend::Emulated => EMULATED,
+    };
+
+    match BACKEND.compare_exchange(UNDECIDED, requested, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => Ok(()),
+        Err(decided) if decided == requested => Ok(()),
+        Err(_) => Err(cas_backend()),
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "emulated-cas")))]
+pub fn compare_and_swap_2(destination: &DoubleU64, expected: &DoubleU64, new_value: &DoubleU64) -> bool {
+    yield_point();
+    if backend() == NATIVE {
+        x86::compare_and_swap_2(destination, expected, new_value)
+    } else {
+        striped::compare_and_swap_2(destination, expected, new_value)
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", not(feature = "emulated-cas"))))]
+pub fn compare_and_swap_2(destination: &DoubleU64, expected: &DoubleU64, new_value: &DoubleU64) -> bool {
+    yield_point();
+    striped::compare_and_swap_2(destination, expected, new_value)
+}
+
+// All operations are sequentially consistent. The queue algorithms were written for the
+// LOCK prefixed x86 instructions, which are full barriers, and rely on that ordering.
+
+pub fn compare_and_swap(destination: &AtomicU64, expected: u64, new_value: u64) -> bool {
+    yield_point();
+    destination.compare_exchange(expected, new_value, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+}
+
+pub fn compare_and_swap_ptr<P>(destination: &AtomicPtr<P>, expected: *mut P, new_value: *mut P) -> bool {
+    yield_point();
+    destination.compare_exchange(expected, new_value, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+}
+
+#[repr(C, align(16))]
+#[derive(Debug)]
+pub struct DoubleU64 {
+    high: AtomicU64,
+    low:  AtomicU64,
+}
+
+pub fn fetch_and_add(destination: &AtomicU64, addend: u64) -> u64 {
+    yield_point();
+    destination.fetch_add(addend, Ordering::SeqCst)
+}
+
+pub fn test_and_set(destination: &AtomicU64) {
+    yield_point();
+    destination.fetch_or(1 << 63, Ordering::SeqCst);
+}
+
+pub fn load(source: &AtomicU64) -> u64 {
+    yield_point();
+    source.load(Ordering::SeqCst)
+}
+
+pub fn load_ptr<P>(source: &AtomicPtr<P>) -> *mut P {
+    yield_point();
+    source.load(Ordering::SeqCst)
+}
+
+pub fn store(destination: &AtomicU64, value: u64) {
+    yield_point();
+    destination.store(value, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use std::thread::spawn;
+    use super::*;
+
+    type CompareAndSwap2 = fn(&DoubleU64, &DoubleU64, &DoubleU64) -> bool;
+
+    // the implementations that work on this machine, regardless of which one was selected
+    fn implementations() -> Vec<CompareAndSwap2> {
+        #[cfg(all(target_arch = "x86_64", not(feature = "emulated-cas")))]
+        {
+            if native_supported() {
+                return vec![striped::compare_and_swap_2, x86::compare_and_swap_2];
+            }
+        }
+        vec![striped::compare_and_swap_2]
+    }
+
+    fn double(high: u64, low: u64) -> DoubleU64 {
+        DoubleU64 { high: AtomicU64::new(high), low: AtomicU64::new(low) }
+    }
+
+    #[test]
+    fn test_fetch_and_add_single_thread() {
+        let x = AtomicU64::new(5);
+        assert_eq!(fetch_and_add(&x, 1), 5);
+        assert_eq!(fetch_and_add(&x, 5), 6);
+        assert_eq!(load(&x), 11);
+    }
+
+    #[test]
+    fn test_compare_and_swap_single_thread() {
+        let x = AtomicU64::new(42);
+        assert!(compare_and_swap(&x, 42, 10));
+
+        assert_eq!(load(&x), 10);
+        assert!(!compare_and_swap(&x, 42, 11));
+
+        assert_eq!(load(&x), 10);
+    }
+
+    #[test]
+    fn test_compare_and_swap_2_single_thread() {
+        for compare_and_swap_2 in implementations() {
+            let x = double(1, 2);
+            assert!(compare_and_swap_2(&x, &double(1, 2), &double(2, 3)));
+            assert_eq!(load(&x.high), 2);
+            assert_eq!(load(&x.low) , 3);
+
+            assert!(!compare_and_swap_2(&x, &double(1, 2), &double(3, 2)));
+            assert_eq!(load(&x.high), 2);
+            assert_eq!(load(&x.low) , 3);
+        }
+    }
+
+    #[test]
+    fn test_compare_and_swap_2_multithreaded() {
+        for compare_and_swap_2 in implementations() {
+            // every thread increments both halves together, so they must never drift apart
+            let x = Arc::new(double(0, 0));
+            let threads = (0..4).map(|_| {
+                let x = x.clone();
+                spawn(move || {
+                    for _ in 0..10000 {
+                        loop {
+                            let high = load(&x.high);
+                            let low = load(&x.low);
+                            if compare_and_swap_2(&x, &double(high, low), &double(high + 1, low + 2)) {
+                                break;
+                            }
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            for thread in threads {
+                assert!(thread.join().is_ok());
+            }
+            assert_eq!(load(&x.high), 40000);
+            assert_eq!(load(&x.low), 80000);
+        }
+    }
+
+    #[test]
+    fn test_backend_is_fixed_once_selected() {
+        let selected = cas_backend();
+        assert_eq!(select_cas_backend(selected), Ok(()));
+
+        let other = if selected == CasBackend::Native { CasBackend::Emulated } else { CasBackend::Native };
+        assert_eq!(select_cas_backend(other), Err(selected));
+        assert_eq!(cas_backend(), selected);
+    }
+
+    #[test]
+    fn test_backend_matches_environment() {
+        match env::var("CONCURRENT_QUEUE_CAS").ok().as_ref().map(|s| &s[..]) {
+            Some("emulated") => assert_eq!(cas_backend(), CasBackend::Emulated),
+            _ if native_supported() => assert_eq!(cas_backend(), CasBackend::Native),
+            _ => assert_eq!(cas_backend(), CasBackend::Emulated),
+        }
+    }
+
+    #[test]
+    fn test_test_and_set() {
+        let x = AtomicU64::new(5);
+        test_and_set(&x);
+        assert_eq!(load(&x), 0x8000000000000005u64);
+    }
+}
@@ -0,0 +1,61 @@
+//! Emulated 16 byte compare-and-swap for targets without `CMPXCHG16B`.
+//!
+//! Every update of a `DoubleU64` takes one of a fixed set of spinlocks, picked by the
+//! address of the destination. Readers don't take the lock, and may see one half updated
+//! before the other, but the queues only ever rely on the compare-and-swap to update
+//! both halves together.
+//!
+//! The loads here aren't scheduling points of the model checker, since the lock must not
+//! be held while another thread runs.
+
+use std::hint;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::DoubleU64;
+
+const STRIPES: usize = 64;
+
+// each lock gets its own cache line, so unrelated nodes don't contend
+#[repr(align(64))]
+struct SpinLock {
+    locked: AtomicBool,
+}
+
+impl SpinLock {
+    fn lock(&self) {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+static LOCKS: [SpinLock; STRIPES] = [const { SpinLock { locked: AtomicBool::new(false) } }; STRIPES];
+
+fn lock_for(destination: &DoubleU64) -> &'static SpinLock {
+    // the low four bits are always zero because of the 16 byte alignment
+    let address = destination as *const DoubleU64 as usize;
+    &LOCKS[(address >> 4) % STRIPES]
+}
+
+pub fn compare_and_swap_2(destination: &DoubleU64, expected: &DoubleU64, new_value: &DoubleU64) -> bool {
+    let expected_high = expected.high.load(Ordering::SeqCst);
+    let expected_low  = expected.low.load(Ordering::SeqCst);
+
+    let lock = lock_for(destination);
+    lock.lock();
+
+    let swapped = destination.high.load(Ordering::SeqCst) == expected_high && destination.low.load(Ordering::SeqCst) == expected_low;
+    if swapped {
+        destination.high.store(new_value.high.load(Ordering::SeqCst), Ordering::SeqCst);
+        destination.low.store(new_value.low.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    lock.unlock();
+    swapped
+}
@@ -1,132 +1,31 @@
+use std::arch::asm;
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub fn compare_and_swap(destination: &u64, expected: u64, new_value: u64) -> bool {
-	let value_at_dest : u64;
-    unsafe {
-        asm!("LOCK CMPXCHG qword ptr [RCX], RBX"
-             : "={rax}"(value_at_dest)   // output
-
-             : "{rbx}"(new_value),
-               "{rcx}"(destination),     // input
-               "{rax}"(expected)
-
-             : "rax", "memory"           // clobbers
-
-             : "intel"                   // options
-        );
-    }
+use super::{DoubleU64, load};
 
-	// this information is also available through the zero flag, but it's
-	// impossible (?) to use that information without doing some sort of
-	// secondary compare outside of the asm! block
-    value_at_dest == expected
-}
-
-#[repr(align(16))]
-#[derive(Debug)]
-pub struct DoubleU64 {
-    high: u64,
-    low:  u64,
-}
-
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub fn compare_and_swap_2(destination: &DoubleU64, expected: &DoubleU64, new_value: &DoubleU64) -> bool { // TODO: return Result to pass back values?
-	let value_at_dest_high : u64;
-	let value_at_dest_low  : u64;
-
+    let expected_high = load(&expected.high);
+    let expected_low  = load(&expected.low);
+    let value_at_dest_high : u64;
+    let value_at_dest_low  : u64;
+
+    // RBX can't be named as an operand, because LLVM may need it as a base pointer, so
+    // the new high value is swapped into it for the duration of the CMPXCHG16B and swapped
+    // back afterwards. In functions that don't need a base pointer, LLVM still allocates
+    // RBX for `reg` operands: release builds put `destination` there, and the first swap
+    // overwrote it. Pinning the destination to RSI keeps it out of RBX.
     unsafe {
-        asm!("LOCK CMPXCHG16B [R8]"
-             : "={rax}"(value_at_dest_high), // output
-               "={rdx}"(value_at_dest_low)
-
-             : "{rbx}"(new_value.high),      // input
-               "{rcx}"(new_value.low),
-               "{r8}"(destination),
-               "{rax}"(expected.high)
-               "{rdx}"(expected.low)
-
-             : "rax", "rdx", "memory"        // clobbers
-
-             : "intel"                       // options
+        asm!("xchg {new_high}, rbx",
+             "lock cmpxchg16b xmmword ptr [rsi]",
+             "xchg {new_high}, rbx",
+             in("rsi") destination,
+             new_high = inout(reg) load(&new_value.high) => _,
+             in("rcx") load(&new_value.low),
+             inout("rax") expected_high => value_at_dest_high,
+             inout("rdx") expected_low => value_at_dest_low,
         );
     }
 
-	// this information is also available through the zero flag, but it's
-	// impossible (?) to use that information without doing some sort of
-	// secondary compare outside of the asm! block
-    value_at_dest_high == expected.high && value_at_dest_low == expected.low
-}
-
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub fn fetch_and_add(destination: &u64, addend: u64) -> u64 {
-	let value_at_dest : u64;
-    unsafe {
-        asm!("LOCK XADD qword ptr [RCX], RBX"
-             : "={rbx}"(value_at_dest)   // output
-
-             : "{rbx}"(addend),          // input
-               "{rcx}"(destination)
-
-             : "rbx", "memory"           // clobbers
-
-             : "intel"                   // options
-        );
-    }
-
-    value_at_dest
-}
-
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub fn test_and_set(destination: &u64) {
-    unsafe {
-        asm!("LOCK BTS qword ptr [RCX], 63"
-             :                                 // output
-             : "{rcx}"(destination)            // input
-             : "rbx", "memory"                 // clobbers
-             : "intel"                         // options
-        );
-    }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn test_fetch_and_add_single_thread() {
-        let mut x = 5;
-        assert_eq!(fetch_and_add(&mut x, 1), 5);
-        assert_eq!(fetch_and_add(&mut x, 5), 6);
-        assert_eq!(x, 11);
-    }
-
-    #[test]
-    fn test_compare_and_swap_single_thread() {
-	    let mut x = 42;
-	    assert!(compare_and_swap(&mut x, 42, 10));
-
-	    assert_eq!(x, 10);
-	    assert!(!compare_and_swap(&mut x, 42, 11));
-
-	    assert_eq!(x, 10);
-    }
-
-    #[test]
-    fn test_compare_and_swap_2_single_thread() {
-	    let mut x = DoubleU64 { high: 1, low: 2 };
-	    assert!(compare_and_swap_2(&mut x, &DoubleU64 { high: 1, low: 2 }, &DoubleU64 { high: 2, low: 3 }));
-	    assert_eq!(x.high, 2);
-	    assert_eq!(x.low , 3);
-
-	    assert!(!compare_and_swap_2(&mut x, &DoubleU64 { high: 1, low: 2 }, &DoubleU64 { high: 3, low: 2 }));
-	    assert_eq!(x.high, 2);
-	    assert_eq!(x.low , 3);
-    }
-
-    #[test]
-    fn test_test_and_set() {
-        let mut x = 5;
-        test_and_set(&mut x);
-        assert_eq!(x, 0x8000000000000005u64);
-    }
+    // this information is also available through the zero flag, but it's simpler to
+    // compare the values that were read than to get the flag out of the asm! block
+    value_at_dest_high == expected_high && value_at_dest_low == expected_low
 }
@@ -0,0 +1,285 @@
+//! Channel with the same interface as `std::sync::mpsc`, backed by an `LCRQ`
+//!
+//! Both ends can be cloned. When the last `Sender` is dropped, the queue is closed, and
+//! receivers get `RecvError` once they've drained it. When the last `Receiver` is
+//! dropped, the queue is closed too, and `send` hands the value back in a `SendError`.
+//!
+//! There's no `sync_channel`. A bounded `LCRQ` makes producers wait for room instead.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub use std::sync::mpsc::{SendError, RecvError, TryRecvError, RecvTimeoutError};
+
+use lcrq::{LCRQ, Closed, DequeueResult};
+
+struct Shared<T> {
+    queue: LCRQ<T>,
+    senders: AtomicUsize,   // live `Sender`s
+    receivers: AtomicUsize, // live `Receiver`s
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Create a channel, returning its sending and receiving ends
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared { queue: LCRQ::new(), senders: AtomicUsize::new(1), receivers: AtomicUsize::new(1) });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Send a value. Fails, handing the value back, if all receivers are gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.queue.enqueue(value).map_err(|Closed(value)| SendError(value))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.queue.close();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a value, waiting for one if the channel is empty. Fails once all senders
+    /// are gone and the channel is drained.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.shared.queue.dequeue_blocking().ok_or(RecvError)
+    }
+
+    /// Receive a value if there is one, without waiting
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.shared.queue.dequeue() {
+            DequeueResult::Value(value) => Ok(value),
+            DequeueResult::Empty        => Err(TryRecvError::Empty),
+            DequeueResult::Closed       => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Receive a value, waiting at most `timeout` for one if the channel is empty
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match self.shared.queue.dequeue_timeout(timeout) {
+            DequeueResult::Value(value) => Ok(value),
+            DequeueResult::Empty        => Err(RecvTimeoutError::Timeout),
+            DequeueResult::Closed       => Err(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Iterate over received values, waiting for each, until all senders are gone
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+
+    /// Iterate over the values that can be received without waiting
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { receiver: self }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.shared.receivers.fetch_add(1, Ordering::SeqCst);
+        Receiver { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.shared.receivers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.queue.close();
+        }
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+pub struct TryIter<'a, T: 'a> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for TryIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::thread::{self, spawn};
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_send_and_recv() {
+        let (sender, receiver) = channel();
+        assert!(sender.send(1).is_ok());
+        assert!(sender.send(2).is_ok());
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn test_recv_timeout_too_long_for_instant() {
+        let (sender, receiver) = channel();
+        assert!(sender.send(1).is_ok());
+        assert_eq!(receiver.recv_timeout(Duration::MAX), Ok(1));
+        drop(sender);
+        assert_eq!(receiver.recv_timeout(Duration::MAX), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn test_try_iter() {
+        let (sender, receiver) = channel();
+        for i in 0..3 {
+            assert!(sender.send(i).is_ok());
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        // stops at an empty channel even though senders are still around
+        assert_eq!(receiver.try_iter().next(), None);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let (sender, receiver) = channel();
+        for i in 0..3 {
+            assert!(sender.send(i).is_ok());
+        }
+        drop(sender);
+        let mut received = Vec::new();
+        for value in receiver {
+            received.push(value);
+        }
+        assert_eq!(received, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_values_that_are_not_send() {
+        // like `std::sync::mpsc`, a channel only has to be Send to cross threads
+        let (sender, receiver) = channel();
+        assert!(sender.send(Rc::new(1)).is_ok());
+        assert_eq!(receiver.recv(), Ok(Rc::new(1)));
+    }
+
+    #[test]
+    fn test_disconnected_after_senders_are_dropped() {
+        let (sender, receiver) = channel();
+        let sender_clone = sender.clone();
+        assert!(sender.send(1).is_ok());
+        drop(sender);
+        assert!(sender_clone.send(2).is_ok());
+        drop(sender_clone);
+
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.recv(), Ok(2));
+        assert_eq!(receiver.recv(), Err(RecvError));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn test_send_fails_after_receivers_are_dropped() {
+        let (sender, receiver) = channel();
+        let receiver_clone = receiver.clone();
+        drop(receiver);
+        assert!(sender.send(1).is_ok());
+        drop(receiver_clone);
+        assert_eq!(sender.send(2), Err(SendError(2)));
+    }
+
+    #[test]
+    fn test_recv_woken_by_disconnect() {
+        let (sender, receiver) = channel::<u64>();
+        let consumer = spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(sender);
+        assert_eq!(consumer.join().unwrap(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_multithreaded() {
+        let (sender, receiver) = channel();
+        let per_sender = 10_000;
+        let senders = (0..4).map(|s| {
+            let sender = sender.clone();
+            spawn(move || {
+                for i in s * per_sender..(s + 1) * per_sender {
+                    sender.send(i).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        drop(sender);
+        let receivers = (0..4).map(|_| {
+            let receiver = receiver.clone();
+            spawn(move || receiver.iter().collect::<Vec<_>>())
+        }).collect::<Vec<_>>();
+        drop(receiver);
+
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        let mut received = receivers.into_iter().flat_map(|r| r.join().unwrap()).collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received, (0..4 * per_sender).collect::<Vec<_>>());
+    }
+}
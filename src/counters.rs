@@ -0,0 +1,74 @@
+//! Per-queue operation counters, kept when the `metrics` feature is on.
+//!
+//! Counters are split into shards on separate cache lines, and every thread adds to its
+//! own shard, so producers and consumers don't all contend for one counter. Without the
+//! feature, `Counters` is empty and `add` does nothing.
+
+/// What a queue counts
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Enqueued,          // values enqueued
+    Dequeued,          // values dequeued
+    RingsClosed,       // CRQs closed, counted when the next one is linked or the queue is closed
+    SegmentsAllocated, // CRQs allocated after the first one
+}
+
+#[cfg(not(feature = "metrics"))]
+pub struct Counters;
+
+#[cfg(not(feature = "metrics"))]
+impl Counters {
+    pub fn new() -> Counters {
+        Counters
+    }
+
+    #[inline(always)]
+    pub fn add(&self, _counter: Counter, _count: usize) {}
+}
+
+#[cfg(feature = "metrics")]
+pub use self::sharded::Counters;
+
+#[cfg(feature = "metrics")]
+mod sharded {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use super::Counter;
+
+    const COUNTERS: usize = 4;
+    const SHARDS: usize = 8;
+
+    #[repr(align(64))]
+    struct Shard {
+        counts: [AtomicU64; COUNTERS],
+    }
+
+    pub struct Counters {
+        shards: [Shard; SHARDS],
+    }
+
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        // threads are spread over the shards in the order they first count something
+        static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+
+    impl Counters {
+        pub fn new() -> Counters {
+            Counters { shards: [const { Shard { counts: [const { AtomicU64::new(0) }; COUNTERS] } }; SHARDS] }
+        }
+
+        pub fn add(&self, counter: Counter, count: usize) {
+            if count > 0 {
+                let shard = SHARD.try_with(|shard| *shard).unwrap_or(0);
+                self.shards[shard].counts[counter as usize].fetch_add(count as u64, Ordering::Relaxed);
+            }
+        }
+
+        /// Sum of the counter over all shards. Counts added meanwhile may be missed.
+        pub fn get(&self, counter: Counter) -> u64 {
+            self.shards.iter().map(|shard| shard.counts[counter as usize].load(Ordering::Relaxed)).sum()
+        }
+    }
+}
@@ -1,160 +1,324 @@
-/// Concurrent ring queue
+//! Concurrent ring queue
 
+use std::cmp;
 use std::ptr;
-use std::mem;
-use std::marker::{Sync, Send};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, AtomicPtr};
 
 use flag_and_u63::FlagAndU63;
 use node::{ Node, NODE_VALUE_EMPTY };
-use atomics::x86::*;
+use atomics::*;
+use stats::{self, Event};
+
+fn as_double_u64(node: &Node) -> &DoubleU64 {
+    // Node and DoubleU64 are both two u64s with 16 byte alignment
+    unsafe { &*(node as *const Node as *const DoubleU64) }
+}
 
 fn compare_and_swap_nodes(node: &Node, expected: &Node, new_value: &Node) -> bool {
-    let mem_current   : &DoubleU64 = unsafe { mem::transmute(node)      };
-    let mem_expected  : &DoubleU64 = unsafe { mem::transmute(expected)  };
-    let mem_new_value : &DoubleU64 = unsafe { mem::transmute(new_value) };
+    let mem_current   = as_double_u64(node);
+    let mem_expected  = as_double_u64(expected);
+    let mem_new_value = as_double_u64(new_value);
 
-    compare_and_swap_2(mem_current, mem_expected, mem_new_value)
+    let swapped = compare_and_swap_2(mem_current, mem_expected, mem_new_value);
+    if !swapped {
+        stats::record(Event::FailedNodeCas);
+    }
+    swapped
 }
 
+/// Default number of slots in a ring
 pub const RING_SIZE: usize = 256;
 
+/// Number of times in a row an enqueuer may fail to claim a slot before it closes the
+/// ring. Dequeuers can keep taking the slots enqueuers are about to write to, and without
+/// this limit an enqueuer could be stuck retrying forever.
+pub const STARVATION_LIMIT: u64 = 16;
+
 // fields are padded to get them on their very own cache lines.
 // This assumes that usize is 64 bits, and a cache line is 64 bytes.
-pub struct CRQ {
-    head: u64,                     // read location
+//
+// The number of slots, `N`, must be a power of two. The ring lives on the heap, since
+// large rings would overflow the stack when creating a CRQ.
+pub struct CRQ<const N: usize = RING_SIZE> {
+    head: AtomicU64,               // read location
     _pad_head: [usize; 7],
     tail_and_closed: FlagAndU63,   // tail (u63, write location), closed queue (1 bit flag)
     _pad_tail: [usize; 7],
-    pub next: *const CRQ,
+    pub next: AtomicPtr<CRQ<N>>,
     _pad_next: [usize; 7],
-    ring: [Node; RING_SIZE]
+    ring: Box<[Node; N]>
 }
 
-unsafe impl Send for CRQ {} // TODO: remove need for this
-unsafe impl Sync for CRQ {}
+#[derive(Debug, PartialEq)]
+pub enum EnqueueError {
+    Closed,        // the queue is closed and won't accept any more values
+    ReservedValue, // `NODE_VALUE_EMPTY` marks empty slots, so it can't be enqueued
+}
 
-pub struct QueueClosed;
+impl Default for CRQ {
+    fn default() -> CRQ {
+        CRQ::new()
+    }
+}
 
 impl CRQ {
+    /// Create a CRQ with the default ring size, `RING_SIZE`
     pub fn new() -> CRQ {
-        /*
-            TODO: It would be nice to do this without unsafe. I can create a vector:
-
-                let ring = (0..RING_SIZE).map(|u| Node::new(u, NODE_VALUE_EMPTY)).collect::<Vec<Node>>();
-
-            But how do I get the underlying array? Getting a slice `&ring[..]` gets me a [Node] which is almost there, but it's unsized.
-        */
-        let ring = unsafe {
-            let mut ring: [Node; RING_SIZE] = mem::uninitialized();
-
-            for (i, element) in ring.iter_mut().enumerate() {
-                let value = Node::new(i as u64, NODE_VALUE_EMPTY, true);
-                ptr::write(element, value);
-            }
+        CRQ::with_ring_size()
+    }
+}
 
-            ring
+impl<const N: usize> CRQ<N> {
+    // Evaluated when a CRQ<N> is created, so an invalid ring size fails to compile
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "the ring size of a CRQ must be a power of two");
+        N - 1
+    };
+
+    /// Create a CRQ with `N` slots in the ring.
+    ///
+    /// ```compile_fail
+    /// use concurrent_queue::crq::CRQ;
+    /// let crq = CRQ::<100>::with_ring_size(); // not a power of two
+    /// ```
+    pub fn with_ring_size() -> CRQ<N> {
+        let _ = Self::MASK; // check the ring size
+        let ring = (0..N).map(|i| Node::new(i as u64, NODE_VALUE_EMPTY, true)).collect::<Vec<Node>>();
+        let ring = match ring.into_boxed_slice().try_into() {
+            Ok(ring) => ring,
+            Err(_) => unreachable!("the ring has exactly N nodes"),
         };
 
-        CRQ { head: 0, tail_and_closed: FlagAndU63::new(false, 0), next: ptr::null(), ring: ring,
+        CRQ { head: AtomicU64::new(0), tail_and_closed: FlagAndU63::new(false, 0), next: AtomicPtr::new(ptr::null_mut()), ring,
               _pad_head: [0; 7], _pad_tail: [0; 7], _pad_next: [0; 7] }
     }
 
-    pub fn enqueue(&self, new_value: u64) -> Result<(), QueueClosed> {
+    pub fn enqueue(&self, new_value: u64) -> Result<(), EnqueueError> {
+        if new_value == NODE_VALUE_EMPTY {
+            return Err(EnqueueError::ReservedValue);
+        }
+
+        let mut attempts = 0;
         loop {
+            attempts += 1;
             let current_tail_and_closed = FlagAndU63::from_repr(fetch_and_add(self.tail_and_closed.ref_combined(), 1));
             let (closed, tail) = current_tail_and_closed.flag_and_value();
 
             if closed {
-                return Err(QueueClosed);
+                return Err(EnqueueError::Closed);
             }
 
-            {
-                let node = &self.ring[tail as usize % RING_SIZE]; // TODO: are we doing a range check? not needed
-                let value = node.value();
+            if self.fill_slot(tail, new_value) {
+                return Ok(());
+            }
 
-                if value == NODE_VALUE_EMPTY {
-                    let (is_safe, index) = node.safe_and_index();
-                    if index <= tail &&
-                       (is_safe || self.head <= tail) &&
-                       compare_and_swap_nodes(node, &Node::new(index, NODE_VALUE_EMPTY, is_safe), &Node::new(tail, new_value, true)) {
-                        return Ok(());
-                    }
-                }
+            if self.close_if_full_or_starving(tail, attempts) {
+                return Err(EnqueueError::Closed);
+            }
+            stats::record(Event::EnqueueRetry);
+        }
+    }
+
+    /// Enqueue `values` in order, claiming a slot for each of them with a single
+    /// fetch-and-add. Returns the number of values enqueued, counting from the front of
+    /// `values`. If that's fewer than all of them, the ring is closed and the rest has to
+    /// go somewhere else.
+    pub fn enqueue_batch(&self, values: &[u64]) -> Result<usize, EnqueueError> {
+        if values.contains(&NODE_VALUE_EMPTY) {
+            return Err(EnqueueError::ReservedValue);
+        }
+
+        let mut enqueued = 0;
+        let mut attempts = 0;
+        while enqueued < values.len() {
+            attempts += 1;
+            // slots claimed beyond the room in the ring are abandoned, and dequeuers have to
+            // go past every one of them, so claim at least one and no more than there's room for
+            let room = (N as u64).saturating_sub(self.tail_and_closed.value().saturating_sub(load(&self.head)));
+            let remaining = &values[enqueued..];
+            let count = (room as usize).clamp(1, remaining.len());
+            let current_tail_and_closed = FlagAndU63::from_repr(fetch_and_add(self.tail_and_closed.ref_combined(), count as u64));
+            let (closed, first_tail) = current_tail_and_closed.flag_and_value();
+
+            if closed {
+                return Ok(enqueued);
             }
 
-            // NOTE: Checking `head < tail` is necessary to avoid underflow in `tail - head`, since
-            // head can advance beyond tail
-            let head = self.head;
-            if (head < tail && (tail - head) as usize >= RING_SIZE) || self.is_starving() {
-                test_and_set(self.tail_and_closed.ref_combined());
-                return Err(QueueClosed);
+            for (i, &value) in remaining[..count].iter().enumerate() {
+                let tail = first_tail + i as u64;
+                if self.fill_slot(tail, value) {
+                    enqueued += 1;
+                    attempts = 0;
+                    continue;
+                }
+
+                // filling the later slots would put their values ahead of this one, so
+                // they're abandoned and the rest of the batch claims new slots
+                if self.close_if_full_or_starving(tail, attempts) {
+                    return Ok(enqueued);
+                }
+                stats::record(Event::EnqueueRetry);
+                break;
             }
+        }
+        Ok(enqueued)
+    }
+
+    /// Try to put `value` in the slot claimed for `tail`
+    fn fill_slot(&self, tail: u64, value: u64) -> bool {
+        let node = &self.ring[tail as usize & Self::MASK];
+        if node.value() != NODE_VALUE_EMPTY {
+            return false;
+        }
+
+        let (is_safe, index) = node.safe_and_index();
+        index <= tail &&
+            (is_safe || load(&self.head) <= tail) &&
+            compare_and_swap_nodes(node, &Node::new(index, NODE_VALUE_EMPTY, is_safe), &Node::new(tail, value, true))
+    }
 
+    /// Close the ring if an enqueuer that failed to fill the slot claimed for `tail` finds
+    /// it full, or has been starving. Returns whether it closed the ring.
+    fn close_if_full_or_starving(&self, tail: u64, attempts: u64) -> bool {
+        // NOTE: Checking `head < tail` is necessary to avoid underflow in `tail - head`, since
+        // head can advance beyond tail
+        let head = load(&self.head);
+        if head < tail && (tail - head) as usize >= N {
+            stats::record(Event::RingClosedFull);
+        } else if self.is_starving(attempts) {
+            stats::record(Event::RingClosedStarving);
+        } else {
+            return false;
         }
+        self.close();
+        true
     }
 
     pub fn dequeue(&self) -> Option<u64> {
         loop {
             let head = fetch_and_add(&self.head, 1);
-            {
-                let node = &self.ring[head as usize % RING_SIZE]; // TODO: are we doing a range check? not needed
+            if let Some(value) = self.take_slot(head) {
+                return Some(value);
+            }
 
-                loop {
-                    let value = node.value();
-                    let (is_safe, index) = node.safe_and_index();
+            let tail = self.tail_and_closed.value();
+            if tail <= head + 1 {
+                self.fix_state();
+                return None;
+            }
+        }
+    }
 
-                    if index > head {
-                        break;
-                    }
+    /// Dequeue values into `buffer`, claiming slots for several of them with a single
+    /// fetch-and-add. Returns the number of values dequeued, which is zero only if the
+    /// ring is empty.
+    pub fn dequeue_batch(&self, buffer: &mut [u64]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
 
-                    if value != NODE_VALUE_EMPTY {
-                        if index == head {
-                            if compare_and_swap_nodes(node, &Node::new(head, value, is_safe), &Node::new(head + RING_SIZE as u64, NODE_VALUE_EMPTY, is_safe)) {
-                                return Some(value)
-                            }
-                        } else {
-                            if compare_and_swap_nodes(node, &Node::new(index, value, is_safe), &Node::new(index, value, false)) {
-                                break;
-                            }
-                        }
-                    } else {
-                        if compare_and_swap_nodes(node, &Node::new(index, NODE_VALUE_EMPTY, is_safe), &Node::new(head + RING_SIZE as u64, NODE_VALUE_EMPTY, is_safe)) {
-                            break;
-                        }
-                    }
+        loop {
+            // claimed slots are lost to enqueuers, so don't claim more than there seem to be values
+            let available = self.tail_and_closed.value().saturating_sub(load(&self.head));
+            let count = (available as usize).clamp(1, buffer.len()) as u64;
+            let first_head = fetch_and_add(&self.head, count);
+
+            let mut dequeued = 0;
+            for head in first_head..first_head + count {
+                if let Some(value) = self.take_slot(head) {
+                    buffer[dequeued] = value;
+                    dequeued += 1;
                 }
             }
+            if dequeued > 0 {
+                return dequeued;
+            }
+
             let tail = self.tail_and_closed.value();
-            if tail <= head + 1 {
+            if tail <= first_head + count {
                 self.fix_state();
+                return 0;
+            }
+        }
+    }
+
+    /// Take the value in the slot claimed for `head`, or mark the slot so that no enqueuer
+    /// fills it afterwards
+    fn take_slot(&self, head: u64) -> Option<u64> {
+        let node = &self.ring[head as usize & Self::MASK];
+
+        loop {
+            let value = node.value();
+            let (is_safe, index) = node.safe_and_index();
+
+            if index > head {
                 return None;
             }
 
+            if value != NODE_VALUE_EMPTY {
+                if index == head {
+                    if compare_and_swap_nodes(node, &Node::new(head, value, is_safe), &Node::new(head + N as u64, NODE_VALUE_EMPTY, is_safe)) {
+                        return Some(value)
+                    }
+                } else {
+                    if compare_and_swap_nodes(node, &Node::new(index, value, is_safe), &Node::new(index, value, false)) {
+                        return None;
+                    }
+                }
+            } else {
+                if compare_and_swap_nodes(node, &Node::new(index, NODE_VALUE_EMPTY, is_safe), &Node::new(head + N as u64, NODE_VALUE_EMPTY, is_safe)) {
+                    return None;
+                }
+            }
         }
     }
 
-    fn is_starving(&self) -> bool {
-        // TODO: IMPLEMENT
-        false
+    /// Number of values in the ring, estimated from `tail - head`. The two counters are
+    /// read one after the other, so while enqueues or dequeues are in flight the estimate
+    /// can be off by the number of them. Slots claimed by enqueuers that failed to fill
+    /// them count as values until dequeuers have passed them.
+    pub fn len_estimate(&self) -> usize {
+        let head = load(&self.head);
+        let tail = self.tail_and_closed.value();
+        // head runs ahead of tail when dequeuers find the ring empty
+        cmp::min(tail.saturating_sub(head), N as u64) as usize
+    }
+
+    /// Whether the ring seems empty, with the same guarantees as `len_estimate`
+    pub fn is_empty(&self) -> bool {
+        self.len_estimate() == 0
+    }
+
+    /// Close the queue, so every later `enqueue` fails
+    pub fn close(&self) {
+        test_and_set(self.tail_and_closed.ref_combined());
+    }
+
+    fn is_starving(&self, attempts: u64) -> bool {
+        attempts >= STARVATION_LIMIT
     }
 
+    /// Move `tail` up to `head` after dequeuers ran past it, so enqueuers don't claim slots
+    /// that dequeuers have already given up on. The closed flag is carried over as it is,
+    /// so a closed ring stays closed, and the compare-and-swap fails if the ring is closed
+    /// meanwhile.
     fn fix_state(&self) {
+        stats::record(Event::FixState);
         loop {
-            let tail_repr = fetch_and_add(self.tail_and_closed.ref_combined(), 0);
-            let head = fetch_and_add(&self.head, 0);
+            let tail_repr = load(self.tail_and_closed.ref_combined());
+            let head = load(&self.head);
 
             if self.tail_and_closed.combined() != tail_repr {
                 continue;
             }
 
-            if head <= tail_repr {
+            let (closed, tail) = FlagAndU63::from_repr(tail_repr).flag_and_value();
+            if head <= tail {
                 return; // nothing to do
             }
 
-            // jh: Since tail_repr < head at this point it means that tail_repr does not have a it's highest bit set (the CLOSED bit).
-            //     Alternatively, it means that head has the highest bit set, and I guess that'll just close the queue?
-
-            if compare_and_swap(self.tail_and_closed.ref_combined(), tail_repr, head) {
+            if compare_and_swap(self.tail_and_closed.ref_combined(), tail_repr, FlagAndU63::new(closed, head).combined()) {
                 return;
             }
         }
@@ -164,18 +328,21 @@ impl CRQ {
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
     use std::ptr;
     use std::thread::spawn;
+    use std::time::Duration;
     use super::*;
     use node::NODE_VALUE_EMPTY;
 
     #[test]
     fn new_crq() {
         let crq = CRQ::new();
-        assert_eq!(crq.head, 0);
+        assert_eq!(load(&crq.head), 0);
         assert_eq!(crq.tail_and_closed.value(), 0);
         assert!(!crq.tail_and_closed.is_flag_set());
-        assert_eq!(crq.next, ptr::null_mut());
+        assert_eq!(crq.next.load(Ordering::SeqCst), ptr::null_mut());
         assert_eq!(crq.ring.len(), RING_SIZE);
 
         for (i, element) in crq.ring.iter().enumerate() {
@@ -191,13 +358,378 @@ mod test {
         for _ in 0..RING_SIZE {
             assert!(crq.enqueue(100).is_ok());
         }
-        assert!(crq.enqueue(100).is_err());
+        assert_eq!(crq.enqueue(100), Err(EnqueueError::Closed));
+    }
+
+    #[test]
+    fn test_small_ring() {
+        let crq = CRQ::<4>::with_ring_size();
+        assert_eq!(crq.ring.len(), 4);
+        for lap in 0..10 {
+            for i in 0..4 {
+                assert_eq!(crq.enqueue(lap * 4 + i), Ok(()));
+            }
+            for i in 0..4 {
+                assert_eq!(crq.dequeue(), Some(lap * 4 + i));
+            }
+        }
+
+        for i in 0..4 {
+            assert_eq!(crq.enqueue(i), Ok(()));
+        }
+        assert_eq!(crq.enqueue(4), Err(EnqueueError::Closed));
+    }
+
+    #[test]
+    fn test_large_ring() {
+        let crq = CRQ::<65536>::with_ring_size();
+        for i in 0..65536 {
+            assert_eq!(crq.enqueue(i), Ok(()));
+        }
+        assert_eq!(crq.enqueue(65536), Err(EnqueueError::Closed));
+        for i in 0..65536 {
+            assert_eq!(crq.dequeue(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_starving_enqueue_closes_ring() {
+        // dequeuers have raced far ahead, so every slot belongs to a later lap and no
+        // enqueue can ever succeed, even though the ring isn't full
+        let mut crq = CRQ::<8>::with_ring_size();
+        crq.head = AtomicU64::new(1 << 40);
+        for (i, node) in crq.ring.iter_mut().enumerate() {
+            *node = Node::new((1 << 40) + i as u64, NODE_VALUE_EMPTY, true);
+        }
+
+        let crq = Arc::new(crq);
+        let (done, finished) = channel();
+        let enq_crq = crq.clone();
+        spawn(move || done.send(enq_crq.enqueue(1)).unwrap());
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(30)), Ok(Err(EnqueueError::Closed)));
+        assert!(crq.tail_and_closed.is_flag_set());
+        assert_eq!(crq.tail_and_closed.value(), STARVATION_LIMIT);
+    }
+
+    #[test]
+    fn test_enqueue_progress_against_dequeuers() {
+        // dequeuers spinning on an empty ring keep pushing head past tail, taking the
+        // slots before the enqueuer gets to them
+        let crq = Arc::new(CRQ::<16>::with_ring_size());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let dequeuers = (0..4).map(|_| {
+            let crq = crq.clone();
+            let stop = stop.clone();
+            spawn(move || {
+                let mut received = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    if crq.dequeue().is_some() {
+                        received += 1;
+                    }
+                }
+                received
+            })
+        }).collect::<Vec<_>>();
+
+        let (done, finished) = channel();
+        let enq_crq = crq.clone();
+        spawn(move || {
+            let mut enqueued = 0;
+            while enq_crq.enqueue(1).is_ok() {
+                enqueued += 1;
+            }
+            done.send(enqueued).unwrap();
+        });
+
+        let enqueued = finished.recv_timeout(Duration::from_secs(30)).expect("enqueuer made no progress");
+        stop.store(true, Ordering::SeqCst);
+        let mut received = 0;
+        for dequeuer in dequeuers {
+            received += dequeuer.join().unwrap();
+        }
+        while crq.dequeue().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, enqueued);
+    }
+
+    #[test]
+    fn test_enqueue_reserved_value() {
+        let crq = CRQ::new();
+        assert_eq!(crq.enqueue(1), Ok(()));
+        assert_eq!(crq.enqueue(u64::MAX), Err(EnqueueError::ReservedValue));
+        assert_eq!(crq.enqueue(2), Ok(()));
+
+        assert_eq!(crq.dequeue(), Some(1));
+        assert_eq!(crq.dequeue(), Some(2));
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_enqueue_largest_value() {
+        let crq = CRQ::new();
+        assert_eq!(crq.enqueue(u64::MAX - 1), Ok(()));
+        assert_eq!(crq.dequeue(), Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_enqueue_batch() {
+        let crq = CRQ::<16>::with_ring_size();
+        assert_eq!(crq.enqueue_batch(&(0..10).collect::<Vec<_>>()), Ok(10));
+        assert_eq!(crq.tail_and_closed.value(), 10);
+        assert_eq!(crq.enqueue_batch(&[]), Ok(0));
+        assert_eq!(crq.enqueue(10), Ok(()));
+        for i in 0..11 {
+            assert_eq!(crq.dequeue(), Some(i));
+        }
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_enqueue_batch_larger_than_ring() {
+        let crq = CRQ::<8>::with_ring_size();
+        assert_eq!(crq.enqueue_batch(&(0..12).collect::<Vec<_>>()), Ok(8));
+        assert!(crq.tail_and_closed.is_flag_set());
+        for i in 0..8 {
+            assert_eq!(crq.dequeue(), Some(i));
+        }
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_enqueue_batch_much_larger_than_ring() {
+        // only the slot that finds the ring full is claimed past its end, so dequeuers
+        // don't have to go past thousands of abandoned slots one at a time
+        let crq = CRQ::<8>::with_ring_size();
+        assert_eq!(crq.enqueue_batch(&(0..10_000).collect::<Vec<_>>()), Ok(8));
+        assert_eq!(crq.tail_and_closed.value(), 8 + 1);
+        for i in 0..8 {
+            assert_eq!(crq.dequeue(), Some(i));
+        }
+        assert_eq!(crq.dequeue(), None);
+        assert!(load(&crq.head) <= 8 + 2);
+    }
+
+    #[test]
+    fn test_enqueue_batch_closed() {
+        let crq = CRQ::new();
+        crq.close();
+        assert_eq!(crq.enqueue_batch(&[1, 2, 3]), Ok(0));
+    }
+
+    #[test]
+    fn test_enqueue_batch_reserved_value() {
+        let crq = CRQ::new();
+        assert_eq!(crq.enqueue_batch(&[1, u64::MAX, 2]), Err(EnqueueError::ReservedValue));
+        assert_eq!(crq.tail_and_closed.value(), 0);
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_enqueue_batch_lost_slot() {
+        // a dequeuer already went past slot 2, so the batch loses it, and gives up the
+        // slots after it too rather than putting values 3 and 4 ahead of value 2
+        let mut crq = CRQ::<8>::with_ring_size();
+        crq.ring[2] = Node::new(8 + 2, NODE_VALUE_EMPTY, true);
+
+        assert_eq!(crq.enqueue_batch(&[0, 1, 2, 3, 4]), Ok(5));
+        assert_eq!(crq.tail_and_closed.value(), 5 + 3);
+        for i in 0..5 {
+            assert_eq!(crq.dequeue(), Some(i));
+        }
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_dequeue_batch() {
+        let crq = CRQ::<16>::with_ring_size();
+        for i in 0..10 {
+            assert!(crq.enqueue(i).is_ok());
+        }
+
+        let mut buffer = [0; 4];
+        assert_eq!(crq.dequeue_batch(&mut buffer), 4);
+        assert_eq!(buffer, [0, 1, 2, 3]);
+        assert_eq!(load(&crq.head), 4);
+
+        // only claims as many slots as there are values
+        let mut buffer = [0; 16];
+        assert_eq!(crq.dequeue_batch(&mut buffer), 6);
+        assert_eq!(buffer[..6], [4, 5, 6, 7, 8, 9]);
+        assert_eq!(load(&crq.head), 10);
+
+        assert_eq!(crq.dequeue_batch(&mut buffer), 0);
+        assert_eq!(crq.dequeue_batch(&mut []), 0);
+        assert!(load(&crq.head) <= crq.tail_and_closed.value());
+        assert!(crq.enqueue(10).is_ok());
+        assert_eq!(crq.dequeue(), Some(10));
+    }
+
+    #[test]
+    fn test_dequeue_batch_empty_and_unsafe_slots() {
+        // slot 1 is empty and slot 2 still holds a value from an earlier lap, so of the
+        // three claimed slots only slot 0 has a value for this lap
+        let mut crq = CRQ::<8>::with_ring_size();
+        crq.head = AtomicU64::new(8);
+        crq.tail_and_closed = FlagAndU63::new(false, 11);
+        crq.ring[0] = Node::new(8, 100, true);
+        crq.ring[1] = Node::new(1, NODE_VALUE_EMPTY, true);
+        crq.ring[2] = Node::new(2, 42, true);
+
+        let mut buffer = [0; 3];
+        assert_eq!(crq.dequeue_batch(&mut buffer), 1);
+        assert_eq!(buffer[0], 100);
+        assert_eq!(load(&crq.head), 11);
+
+        // the empty slot is pushed to the next lap, and the stale value is marked unsafe
+        assert_eq!(crq.ring[1].index(), 9 + 8);
+        assert_eq!(crq.ring[1].value(), NODE_VALUE_EMPTY);
+        assert_eq!(crq.ring[2].index(), 2);
+        assert!(!crq.ring[2].is_safe());
+    }
+
+    #[test]
+    fn test_dequeue_batch_multithreaded() {
+        let crq = Arc::new(CRQ::<1024>::with_ring_size());
+        let enq_crq = crq.clone();
+        let producer = spawn(move || {
+            let mut enqueued = Vec::new();
+            for i in 0..100_000 {
+                if enq_crq.enqueue(i).is_err() {
+                    break;
+                }
+                enqueued.push(i);
+            }
+            enq_crq.close();
+            enqueued
+        });
+
+        let consumers = (0..3).map(|_| {
+            let crq = crq.clone();
+            spawn(move || {
+                let mut received = Vec::new();
+                let mut buffer = [0; 16];
+                loop {
+                    let closed = crq.tail_and_closed.is_flag_set();
+                    let dequeued = crq.dequeue_batch(&mut buffer);
+                    received.extend_from_slice(&buffer[..dequeued]);
+                    if dequeued == 0 && closed {
+                        return received;
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let enqueued = producer.join().unwrap();
+        let mut received = consumers.into_iter().flat_map(|c| c.join().unwrap()).collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received, enqueued);
+    }
+
+    #[test]
+    fn test_len_estimate() {
+        let crq = CRQ::<8>::with_ring_size();
+        assert_eq!(crq.len_estimate(), 0);
+        assert!(crq.is_empty());
+        for i in 0..5 {
+            assert!(crq.enqueue(i).is_ok());
+        }
+        assert_eq!(crq.len_estimate(), 5);
+        crq.dequeue();
+        crq.dequeue();
+        assert_eq!(crq.len_estimate(), 3);
+        assert!(!crq.is_empty());
+
+        // the closed bit isn't counted
+        crq.close();
+        assert_eq!(crq.len_estimate(), 3);
+    }
+
+    #[test]
+    fn test_len_estimate_head_past_tail() {
+        let crq = CRQ::<8>::with_ring_size();
+        assert!(crq.enqueue(1).is_ok());
+        crq.head.store(5, Ordering::SeqCst);
+        assert_eq!(crq.len_estimate(), 0);
+    }
+
+    #[test]
+    fn test_len_estimate_full_ring() {
+        let crq = CRQ::<8>::with_ring_size();
+        for i in 0..8 {
+            assert!(crq.enqueue(i).is_ok());
+        }
+        assert_eq!(crq.enqueue(8), Err(EnqueueError::Closed));
+        assert_eq!(crq.len_estimate(), 8);
+    }
+
+    #[test]
+    fn test_fix_state_moves_tail_to_head() {
+        let crq = CRQ::<4>::with_ring_size();
+        assert!(crq.enqueue(1).is_ok());
+        assert_eq!(crq.dequeue(), Some(1));
+        assert_eq!(crq.dequeue(), None);
+        assert_eq!(crq.tail_and_closed.flag_and_value(), (false, 2));
+        assert!(crq.enqueue(2).is_ok());
+        assert_eq!(crq.dequeue(), Some(2));
+    }
+
+    #[test]
+    fn test_fix_state_keeps_ring_closed() {
+        let crq = CRQ::<4>::with_ring_size();
+        assert!(crq.enqueue(1).is_ok());
+        crq.close();
+        assert_eq!(crq.dequeue(), Some(1));
+
+        // every dequeue from the drained ring drives head past tail, and tail follows it
+        for head in 2..6 {
+            assert_eq!(crq.dequeue(), None);
+            assert_eq!(load(&crq.head), head);
+            assert_eq!(crq.tail_and_closed.flag_and_value(), (true, head));
+        }
+        assert_eq!(crq.enqueue(2), Err(EnqueueError::Closed));
+        assert_eq!(crq.enqueue_batch(&[2, 3]), Ok(0));
+        assert_eq!(crq.dequeue(), None);
+    }
+
+    #[test]
+    fn test_fix_state_far_past_closed_tail() {
+        let crq = CRQ::<4>::with_ring_size();
+        crq.close();
+        crq.head.store(1000, Ordering::SeqCst);
+        crq.fix_state();
+        assert_eq!(crq.tail_and_closed.flag_and_value(), (true, 1000));
+        assert_eq!(crq.enqueue(1), Err(EnqueueError::Closed));
+    }
+
+    #[test]
+    fn test_fix_state_closing_concurrently() {
+        // rings closed while dequeuers fix their state must never reopen
+        for _ in 0..1000 {
+            let crq = Arc::new(CRQ::<4>::with_ring_size());
+            let dequeuers = (0..2).map(|_| {
+                let crq = crq.clone();
+                spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(crq.dequeue(), None);
+                    }
+                })
+            }).collect::<Vec<_>>();
+            crq.close();
+            for dequeuer in dequeuers {
+                dequeuer.join().unwrap();
+            }
+            assert!(crq.tail_and_closed.is_flag_set());
+            assert_eq!(crq.enqueue(1), Err(EnqueueError::Closed));
+        }
     }
 
     #[test]
     fn test_deque_empty() {
         let crq = CRQ::new();
-        assert!(crq.dequeue() == None);
+        assert_eq!(crq.dequeue(), None);
     }
 
     #[test]
@@ -230,11 +762,8 @@ mod test {
 
         let producer = spawn(move || {
             for i in 0..RING_SIZE {
-                loop {
-                    match prod_crq.enqueue(100 + i as u64) {
-                        Ok(()) => { break; },
-                        Err(QueueClosed) => { panic!("Queue closed"); },
-                    }
+                if let Err(error) = prod_crq.enqueue(100 + i as u64) {
+                    panic!("Enqueue failed: {:?}", error);
                 }
             }
         });
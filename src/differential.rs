@@ -0,0 +1,330 @@
+//! Differential tests of `CRQ` and `LCRQ` against a `VecDeque` model
+//!
+//! Random sequences of operations run on a queue and on the model side by side, and
+//! every result is compared. The sequences come in bursts of enqueues and of dequeues,
+//! so small rings fill up, wrap around, close, and make an `LCRQ` hop to new segments.
+//! When a sequence fails, it's shrunk by dropping operations and making batches smaller
+//! for as long as it keeps failing, and the test panics with the seed and what's left.
+//!
+//! The seed can be set with the environment variable `CONCURRENT_QUEUE_SEED`.
+
+use std::collections::VecDeque;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+
+use crq::{CRQ, EnqueueError};
+use lcrq::{LCRQ, Closed, DequeueResult};
+
+/// Seedable pseudo random number generator, SplitMix64
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// An operation on a queue. The values to enqueue are numbered as the sequence runs, so
+/// operations can be dropped while shrinking without making the values repeat.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Enqueue,
+    EnqueueBatch(usize),
+    Dequeue,
+    DequeueBatch(usize),
+    Close,
+}
+
+impl Op {
+    /// Simpler operations to try in place of this one while shrinking
+    fn simpler(&self) -> Vec<Op> {
+        match *self {
+            Op::EnqueueBatch(count) if count > 1 => vec![Op::Enqueue, Op::EnqueueBatch(count - 1)],
+            Op::EnqueueBatch(_)                  => vec![Op::Enqueue],
+            Op::DequeueBatch(count) if count > 1 => vec![Op::Dequeue, Op::DequeueBatch(count - 1)],
+            Op::DequeueBatch(_)                  => vec![Op::Dequeue],
+            _ => vec![],
+        }
+    }
+}
+
+/// A sequence of up to `max_len` operations, in bursts of enqueues and dequeues
+pub fn generate(rng: &mut Rng, max_len: usize) -> Vec<Op> {
+    let len = rng.below(max_len as u64 + 1) as usize;
+    let mut ops = Vec::with_capacity(len);
+    let mut enqueuing = true;
+    while ops.len() < len {
+        if rng.below(8) == 0 {
+            enqueuing = !enqueuing;
+        }
+        let op = match rng.below(100) {
+            0 => Op::Close,
+            1..=59 if enqueuing => Op::Enqueue,
+            60..=89 if enqueuing => Op::EnqueueBatch(1 + rng.below(8) as usize),
+            1..=59 => Op::Dequeue,
+            60..=89 => Op::DequeueBatch(1 + rng.below(8) as usize),
+            _ if enqueuing => Op::Dequeue,
+            _ => Op::Enqueue,
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+/// Run `ops` on a `CRQ` and the model. A single thread never makes an enqueuer starve,
+/// so the ring only closes when an enqueuer finds it full, or on `Close`.
+pub fn run_crq<const N: usize>(ops: &[Op]) -> Result<(), String> {
+    let crq = CRQ::<N>::with_ring_size();
+    let mut model = VecDeque::new();
+    let mut closed = false;
+    let mut next_value = 0;
+    for (step, op) in ops.iter().enumerate() {
+        let fail = |message: String| Err(format!("step {} ({:?}): {}", step, op, message));
+        match *op {
+            Op::Enqueue => {
+                let expected = if closed || model.len() == N { Err(EnqueueError::Closed) } else { Ok(()) };
+                let result = crq.enqueue(next_value);
+                if result != expected {
+                    return fail(format!("enqueue returned {:?}, expected {:?}", result, expected));
+                }
+                match expected {
+                    Ok(()) => model.push_back(next_value),
+                    Err(_) => closed = true,
+                }
+                next_value += 1;
+            }
+            Op::EnqueueBatch(count) => {
+                let values = (next_value..next_value + count as u64).collect::<Vec<_>>();
+                next_value += count as u64;
+                let expected = if closed { 0 } else { count.min(N - model.len()) };
+                let result = crq.enqueue_batch(&values);
+                if result != Ok(expected) {
+                    return fail(format!("enqueue_batch returned {:?}, expected Ok({})", result, expected));
+                }
+                model.extend(&values[..expected]);
+                closed |= expected < count;
+            }
+            Op::Dequeue => {
+                let result = crq.dequeue();
+                let expected = model.pop_front();
+                if result != expected {
+                    return fail(format!("dequeue returned {:?}, expected {:?}", result, expected));
+                }
+            }
+            Op::DequeueBatch(count) => {
+                let mut buffer = vec![0; count];
+                let dequeued = crq.dequeue_batch(&mut buffer);
+                let expected = model.drain(..count.min(model.len())).collect::<Vec<_>>();
+                if buffer[..dequeued] != expected[..] {
+                    return fail(format!("dequeue_batch returned {:?}, expected {:?}", &buffer[..dequeued], expected));
+                }
+            }
+            Op::Close => {
+                crq.close();
+                closed = true;
+            }
+        }
+        if crq.len_estimate() < model.len() {
+            return fail(format!("len_estimate is {}, but {} values are queued", crq.len_estimate(), model.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Run `ops` on an unbounded `LCRQ` and the model
+pub fn run_lcrq<const N: usize>(ops: &[Op]) -> Result<(), String> {
+    let lcrq = LCRQ::<u64, N>::with_ring_size();
+    let mut model = VecDeque::new();
+    let mut closed = false;
+    let mut next_value = 0;
+    for (step, op) in ops.iter().enumerate() {
+        let fail = |message: String| Err(format!("step {} ({:?}): {}", step, op, message));
+        match *op {
+            Op::Enqueue => {
+                let result = lcrq.enqueue(next_value);
+                let expected = if closed { Err(Closed(next_value)) } else { Ok(()) };
+                if result != expected {
+                    return fail(format!("enqueue returned {:?}, expected {:?}", result, expected));
+                }
+                if !closed {
+                    model.push_back(next_value);
+                }
+                next_value += 1;
+            }
+            Op::EnqueueBatch(count) => {
+                let values = (next_value..next_value + count as u64).collect::<Vec<_>>();
+                next_value += count as u64;
+                let result = lcrq.enqueue_batch(&values);
+                let expected = if closed { Err(Closed(values.clone())) } else { Ok(()) };
+                if result != expected {
+                    return fail(format!("enqueue_batch returned {:?}, expected {:?}", result, expected));
+                }
+                if !closed {
+                    model.extend(values);
+                }
+            }
+            Op::Dequeue => {
+                let result = lcrq.dequeue();
+                let expected = match model.pop_front() {
+                    Some(value) => DequeueResult::Value(value),
+                    None if closed => DequeueResult::Closed,
+                    None => DequeueResult::Empty,
+                };
+                if result != expected {
+                    return fail(format!("dequeue returned {:?}, expected {:?}", result, expected));
+                }
+            }
+            Op::DequeueBatch(count) => {
+                let mut values = Vec::new();
+                lcrq.drain_into(&mut values, count);
+                let expected = model.drain(..count.min(model.len())).collect::<Vec<_>>();
+                if values != expected {
+                    return fail(format!("drain_into returned {:?}, expected {:?}", values, expected));
+                }
+            }
+            Op::Close => {
+                lcrq.close();
+                closed = true;
+            }
+        }
+        if lcrq.is_closed() != closed {
+            return fail(format!("is_closed is {}, expected {}", lcrq.is_closed(), closed));
+        }
+        if lcrq.len_estimate() < model.len() {
+            return fail(format!("len_estimate is {}, but {} values are queued", lcrq.len_estimate(), model.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Run `run` on a sequence, turning a panic into a failure
+fn run_caught<F: Fn(&[Op]) -> Result<(), String>>(run: &F, ops: &[Op]) -> Result<(), String> {
+    match panic::catch_unwind(AssertUnwindSafe(|| run(ops))) {
+        Ok(result) => result,
+        Err(payload) => Err(payload.downcast_ref::<&str>().map(|message| message.to_string())
+                               .or_else(|| payload.downcast_ref::<String>().cloned())
+                               .unwrap_or_else(|| "panicked".to_string())),
+    }
+}
+
+/// Shrink a failing sequence to one that still fails, but where dropping any single
+/// operation or simplifying any batch makes it pass
+pub fn shrink<F: Fn(&[Op]) -> Result<(), String>>(run: &F, mut ops: Vec<Op>) -> Vec<Op> {
+    let fails = |ops: &[Op]| run_caught(run, ops).is_err();
+    let mut shrunk = true;
+    while shrunk {
+        shrunk = false;
+
+        // drop chunks of operations, halving the chunk size down to single operations
+        let mut chunk = ops.len().div_ceil(2);
+        while chunk > 0 {
+            let mut start = 0;
+            while start + chunk <= ops.len() {
+                let candidate = ops[..start].iter().chain(&ops[start + chunk..]).cloned().collect::<Vec<_>>();
+                if fails(&candidate) {
+                    ops = candidate;
+                    shrunk = true;
+                } else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+
+        for i in 0..ops.len() {
+            for simpler in ops[i].simpler() {
+                let mut candidate = ops.clone();
+                candidate[i] = simpler;
+                if fails(&candidate) {
+                    ops = candidate;
+                    shrunk = true;
+                    break;
+                }
+            }
+        }
+    }
+    ops
+}
+
+/// Run `cases` random sequences of up to `max_len` operations, and panic with a shrunk
+/// reproducer if any of them fails
+pub fn check<F: Fn(&[Op]) -> Result<(), String>>(name: &str, cases: usize, max_len: usize, run: F) {
+    let seed = env::var("CONCURRENT_QUEUE_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or(0x5eed);
+    let mut rng = Rng::new(seed);
+    for case in 0..cases {
+        let ops = generate(&mut rng, max_len);
+        if run_caught(&run, &ops).is_err() {
+            let minimal = shrink(&run, ops);
+            let failure = run_caught(&run, &minimal).unwrap_err();
+            panic!("{} failed in case {} with CONCURRENT_QUEUE_SEED={}\nminimal sequence: {:?}\n{}", name, case, seed, minimal, failure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+        let from_a = (0..10).map(|_| a.next_u64()).collect::<Vec<_>>();
+        assert_eq!(from_a, (0..10).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(from_a, (0..10).map(|_| c.next_u64()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shrink() {
+        // fails whenever a batch of at least two is followed by a close, at any distance
+        let run = |ops: &[Op]| {
+            let batch = ops.iter().position(|op| matches!(*op, Op::EnqueueBatch(count) if count >= 2));
+            match batch {
+                Some(batch) if ops[batch..].contains(&Op::Close) => Err("batch before close".to_string()),
+                _ => Ok(()),
+            }
+        };
+        let mut ops = vec![Op::Enqueue, Op::EnqueueBatch(7), Op::Dequeue, Op::DequeueBatch(3)];
+        ops.extend(vec![Op::Dequeue; 20]);
+        ops.extend(vec![Op::Close, Op::Enqueue]);
+        assert_eq!(shrink(&run, ops), vec![Op::EnqueueBatch(2), Op::Close]);
+    }
+
+    #[test]
+    fn test_check_reports_minimal_sequence() {
+        let run = |ops: &[Op]| {
+            if ops.iter().filter(|op| **op == Op::Dequeue).count() >= 3 { Err("three dequeues".to_string()) } else { Ok(()) }
+        };
+        let message = panic::catch_unwind(|| check("three dequeues", 100, 50, run)).unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(message.contains("minimal sequence: [Dequeue, Dequeue, Dequeue]"), "{}", message);
+    }
+
+    #[test]
+    fn test_crq() {
+        check("CRQ<2>", 500, 100, run_crq::<2>);
+        check("CRQ<4>", 500, 100, run_crq::<4>);
+        check("CRQ<16>", 200, 200, run_crq::<16>);
+    }
+
+    #[test]
+    fn test_lcrq() {
+        check("LCRQ<2>", 500, 100, run_lcrq::<2>);
+        check("LCRQ<4>", 500, 100, run_lcrq::<4>);
+        check("LCRQ<16>", 200, 200, run_lcrq::<16>);
+    }
+}
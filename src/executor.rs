@@ -0,0 +1,56 @@
+//! Minimal executor for testing futures without an external runtime
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Wakes up the thread that created it, and remembers that it was woken
+pub struct ThreadWaker {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+impl ThreadWaker {
+    pub fn new() -> Arc<ThreadWaker> {
+        Arc::new(ThreadWaker { thread: thread::current(), woken: AtomicBool::new(false) })
+    }
+
+    /// Whether the waker was woken since the last call
+    pub fn take_woken(&self) -> bool {
+        self.woken.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+/// Poll `future` once with `waker`
+pub fn poll_once<F: Future + Unpin>(future: &mut F, waker: &Arc<ThreadWaker>) -> Poll<F::Output> {
+    let waker = Waker::from(waker.clone());
+    Pin::new(future).poll(&mut Context::from_waker(&waker))
+}
+
+/// Run `future` to completion on the current thread, parking it while the future is pending
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = ThreadWaker::new();
+    loop {
+        if let Poll::Ready(output) = poll_once(&mut future, &waker) {
+            return output;
+        }
+        while !waker.take_woken() {
+            thread::park();
+        }
+    }
+}
@@ -1,98 +1,829 @@
-/// Linked concurrent ring queue
+//! Linked concurrent ring queue
 
-use std::ptr;
+use std::cmp;
 use std::mem;
-use std::marker::{Sync, Send};
+use std::ptr;
+use std::slice;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::{Sync, Send, PhantomData};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicPtr, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use crq::CRQ;
-use atomics::x86::compare_and_swap;
+use crq::{CRQ, RING_SIZE};
+use flag_and_u63::FlagAndU63;
+use atomics::{compare_and_swap, compare_and_swap_ptr, fetch_and_add, load, load_ptr, store};
+use wait::{WaitQueue, WakerList};
+use stats::{self, Event};
+use counters::{Counter, Counters};
+#[cfg(feature = "metrics")]
+use metrics::QueueMetrics;
 
-// `head` and `tail` are padded to get them on their very own cache lines.
+// `head` and `tail` are padded to get them on their very own cache lines. `consumers`
+// shares the line with `tail`, since producers that enqueue touch both.
 // This assumes that usize is 64 bits, and a cache line is 64 bytes.
-pub struct LCRQ {
-    tail: *const CRQ,
-    _pad_tail: [usize; 7],
-    head: *const CRQ,
+//
+// Values are boxed and the CRQs only store the pointers to them. A pointer never equals
+// `NODE_VALUE_EMPTY`, so any `T` can be queued.
+//
+// Every CRQ has `N` slots, which must be a power of two. Small rings are cheaper to scan
+// and stay in cache, while large rings need new CRQs to be allocated less often.
+pub struct LCRQ<T, const N: usize = RING_SIZE> {
+    tail: AtomicPtr<CRQ<N>>,
+    consumers: WaitQueue, // consumers blocked in `dequeue_blocking` or `dequeue_timeout`
+    _pad_tail: [u8; TAIL_PADDING],
+    head: AtomicPtr<CRQ<N>>,
     _pad_head: [usize; 7],
+    epoch: AtomicU64,                      // global epoch used for reclaiming retired CRQs
+    registry: Arc<Registry>,               // participant records of the threads using the queue
+    live_segments: AtomicU64,              // number of allocated CRQs that have not been freed yet
+    linked_segments: AtomicU64,            // number of CRQs from `head` to the end of the list
+    max_segments: u64,                     // limit for `linked_segments`, u64::MAX when unbounded
+    producers: WaitQueue,                  // producers blocked in `enqueue` until there's room
+    wakers: WakerList,                     // futures returned by `recv_async` that wait for a value
+    counters: Counters,                    // operations on this queue, see `metrics`
+    _values: PhantomData<Box<T>>,
+}
+
+const TAIL_PADDING: usize = (64 - mem::size_of::<usize>()).saturating_sub(mem::size_of::<WaitQueue>());
+
+/// Number of values `drain_into` dequeues at a time
+const DRAIN_CHUNK: usize = 64;
+
+unsafe impl<T: Send, const N: usize> Send for LCRQ<T, N> {} // TODO: remove need for this
+unsafe impl<T: Send, const N: usize> Sync for LCRQ<T, N> {}
+
+/// Returned by `enqueue` when the queue has been closed, handing the value back
+#[derive(Debug, PartialEq)]
+pub struct Closed<T>(pub T);
+
+/// Returned by `try_enqueue` when the value can't be enqueued, handing it back
+#[derive(Debug, PartialEq)]
+pub enum TryEnqueueError<T> {
+    Full(T),   // the queue is bounded and has no room right now
+    Closed(T), // the queue has been closed
+}
+
+impl<T> TryEnqueueError<T> {
+    fn map<U, F: FnOnce(T) -> U>(self, f: F) -> TryEnqueueError<U> {
+        match self {
+            TryEnqueueError::Full(value)   => TryEnqueueError::Full(f(value)),
+            TryEnqueueError::Closed(value) => TryEnqueueError::Closed(f(value)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DequeueResult<T> {
+    Value(T),
+    Empty,  // nothing to dequeue right now, but more values may arrive
+    Closed, // the queue is closed and every value has been dequeued
+}
+
+impl<T> DequeueResult<T> {
+    /// The dequeued value, if there was one
+    pub fn value(self) -> Option<T> {
+        match self {
+            DequeueResult::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Closing an LCRQ closes the last CRQ and sets its `next` to this marker, so no CRQ can
+// ever be appended after it. The dangling pointer is never the address of a real CRQ.
+fn closed_marker<const N: usize>() -> *mut CRQ<N> {
+    ptr::dangling_mut()
+}
+
+/*
+    Retired CRQs are reclaimed using epoch based reclamation. Every operation pins the
+    global epoch in a participant record before touching any CRQ, and unpins it when done.
+    The global epoch can only advance when every pinned participant has observed the
+    current epoch, so a CRQ unlinked and retired in epoch `e` can't be referenced by any
+    operation once the global epoch has reached `e + 2`.
+
+    Every thread owns a participant record of each queue it uses, and finds it in a thread
+    local cache, so pinning is a store to a cache line no other thread writes to. When the
+    thread exits, its records become idle and can be taken over by new threads, along with
+    the CRQs they retired.
+*/
+struct Participant {
+    active: AtomicBool,  // owned by a thread
+    epoch: FlagAndU63,   // pinned (1 bit flag), the epoch it was pinned in (u63). Only written by the owner
+    guards: Cell<usize>, // number of live guards of the owner, which only it touches
+    garbage: UnsafeCell<Vec<(u64, *mut ())>>, // retired CRQs and their epoch. Only touched while pinned
+    next: *mut Participant,
+}
+
+/// The participant records of a queue. Threads keep it alive in their cache after the
+/// queue has been dropped, until they notice.
+struct Registry {
+    participants: AtomicPtr<Participant>, // linked list, never shrinks
+    queue_dropped: AtomicBool,
+}
+
+unsafe impl Send for Registry {}
+unsafe impl Sync for Registry {}
+
+impl Registry {
+    fn new() -> Arc<Registry> {
+        Arc::new(Registry { participants: AtomicPtr::new(ptr::null_mut()), queue_dropped: AtomicBool::new(false) })
+    }
+
+    /// Take over an idle participant record, or register a new one
+    fn claim(registry: &Arc<Registry>) -> Handle {
+        let mut current = load_ptr(&registry.participants);
+        while !current.is_null() {
+            let participant = unsafe { &*current };
+            if participant.active.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return Handle { registry: registry.clone(), participant };
+            }
+            current = participant.next;
+        }
+
+        let participant = Box::into_raw(Box::new(Participant {
+            active: AtomicBool::new(true), epoch: FlagAndU63::new(false, 0), guards: Cell::new(0),
+            garbage: UnsafeCell::new(Vec::new()), next: ptr::null_mut(),
+        }));
+        loop {
+            let head = load_ptr(&registry.participants);
+            unsafe { (*participant).next = head; }
+            if compare_and_swap_ptr(&registry.participants, head, participant) {
+                return Handle { registry: registry.clone(), participant };
+            }
+        }
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        // the queue has freed all garbage when it was dropped
+        let mut current = *self.participants.get_mut();
+        while !current.is_null() {
+            let participant = unsafe { Box::from_raw(current) };
+            current = participant.next;
+        }
+    }
+}
+
+/// A participant record owned by the current thread, which goes back to the registry
+/// when the handle is dropped
+struct Handle {
+    registry: Arc<Registry>,
+    participant: *const Participant,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { (*self.participant).active.store(false, Ordering::SeqCst); }
+    }
+}
+
+/// The handles a thread owns, keyed by the address of their registry. The address can't
+/// be reused while the handle keeps the registry alive.
+struct Handles {
+    last: Option<(*const Registry, *const Participant)>, // the registry looked up last, checked before hashing
+    by_registry: HashMap<*const Registry, Handle>,
+}
+
+impl Handles {
+    /// The participant record this thread owns in `registry`, claiming one the first time
+    fn participant(&mut self, registry: &Arc<Registry>) -> *const Participant {
+        let key = Arc::as_ptr(registry);
+        if let Some((last, participant)) = self.last {
+            if last == key {
+                return participant;
+            }
+        }
+
+        let participant = match self.by_registry.get(&key) {
+            Some(handle) => handle.participant,
+            None => {
+                // handles of queues that have been dropped meanwhile are no use anymore
+                self.by_registry.retain(|_, handle| !handle.registry.queue_dropped.load(Ordering::SeqCst));
+                let handle = Registry::claim(registry);
+                let participant = handle.participant;
+                self.by_registry.insert(key, handle);
+                participant
+            }
+        };
+        self.last = Some((key, participant));
+        participant
+    }
+
+    fn remove(&mut self, registry: &Arc<Registry>) {
+        let key = Arc::as_ptr(registry);
+        if self.last.is_some_and(|(last, _)| last == key) {
+            self.last = None;
+        }
+        self.by_registry.remove(&key);
+    }
+
+    #[cfg(feature = "model")]
+    fn clear(&mut self) {
+        self.last = None;
+        self.by_registry.clear();
+    }
+}
+
+thread_local! {
+    // a handle for every queue this thread has pinned
+    static HANDLES: RefCell<Handles> = RefCell::new(Handles { last: None, by_registry: HashMap::new() });
+}
+
+/// Hand back the participant records of the current thread, like it does when it exits.
+/// Threads of the model checker do this before they finish, since their thread locals
+/// are destroyed at a point the schedule doesn't control.
+#[cfg(feature = "model")]
+pub(crate) fn release_participants() {
+    let _ = HANDLES.try_with(|handles| handles.borrow_mut().clear());
+}
+
+struct Guard<'a, T: 'a, const N: usize> {
+    queue: &'a LCRQ<T, N>,
+    participant: &'a Participant,
+    _handle: Option<Handle>, // a participant claimed just for this guard, once the thread's cache is gone
 }
 
-unsafe impl Send for LCRQ {} // TODO: remove need for this
-unsafe impl Sync for LCRQ {}
+impl<'a, T, const N: usize> Guard<'a, T, N> {
+    /// Hand over a CRQ that has been unlinked from the queue, to be freed once no
+    /// operation can still be reading it
+    fn retire(&self, crq: *mut CRQ<N>) {
+        let epoch = load(&self.queue.epoch);
+        let garbage = unsafe { &mut *self.participant.garbage.get() };
+        garbage.push((epoch, crq as *mut ()));
+
+        let global_epoch = self.queue.try_advance_epoch();
+        let queue = self.queue;
+        garbage.retain(|&(epoch, crq)| {
+            if epoch + 2 <= global_epoch {
+                unsafe { queue.free_segment(crq as *mut CRQ<N>); }
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
 
-fn compare_and_swap_crq_ptr(destination: &*const CRQ, expected: *const CRQ, new_value: *const CRQ) -> bool {
-    unsafe {
-        compare_and_swap(mem::transmute(destination), mem::transmute(expected), mem::transmute(new_value))
+impl<'a, T, const N: usize> Drop for Guard<'a, T, N> {
+    fn drop(&mut self) {
+        let guards = self.participant.guards.get() - 1;
+        self.participant.guards.set(guards);
+        if guards == 0 {
+            store(self.participant.epoch.ref_combined(), FlagAndU63::new(false, self.participant.epoch.value()).combined());
+        }
     }
 }
 
-fn untracked_pointer(crq: CRQ) -> *const CRQ {
-    unsafe { mem::transmute(Box::new(crq)) }
+fn into_raw_value<T>(value: T) -> u64 {
+    Box::into_raw(Box::new(value)) as usize as u64
+}
+
+/// Take back ownership of a value created by `into_raw_value`. Must only be called once
+/// per value.
+unsafe fn from_raw_value<T>(raw: u64) -> T {
+    *Box::from_raw(raw as usize as *mut T)
+}
+
+impl<T> Default for LCRQ<T> {
+    fn default() -> LCRQ<T> {
+        LCRQ::new()
+    }
 }
 
-impl LCRQ {
-    pub fn new() -> LCRQ {
-        //let crq = untracked_crq();
-        let crq = untracked_pointer(CRQ::new());
-        LCRQ { tail: crq, head: crq, _pad_tail: [0; 7], _pad_head: [0; 7] }
+impl<T> LCRQ<T> {
+    /// Create an LCRQ whose CRQs have the default ring size, `RING_SIZE`
+    pub fn new() -> LCRQ<T> {
+        LCRQ::with_ring_size()
+    }
+
+    /// Create a bounded LCRQ whose CRQs have the default ring size, `RING_SIZE`. See
+    /// `bounded_with_ring_size`.
+    pub fn bounded(max_segments: usize) -> LCRQ<T> {
+        LCRQ::bounded_with_ring_size(max_segments)
+    }
+}
+
+impl<T, const N: usize> LCRQ<T, N> {
+    /// Create an LCRQ whose CRQs have `N` slots each
+    pub fn with_ring_size() -> LCRQ<T, N> {
+        LCRQ::with_max_segments(u64::MAX)
+    }
+
+    /// Create an LCRQ whose CRQs have `N` slots each, and that never links more than
+    /// `max_segments` of them, so it holds at most `max_segments * N` values. A CRQ that
+    /// closes early because an enqueuer was starving still counts as a whole segment.
+    ///
+    /// The bound counts CRQs rather than values, because every CRQ has its own head and
+    /// tail, and there's no pair of counters for the whole queue whose difference could be
+    /// checked when enqueuing. So the queue can be full with fewer values in it, while the
+    /// CRQ at the head drains or when CRQs closed early.
+    ///
+    /// Panics if `max_segments` is less than 2, since the queue needs room for a new CRQ
+    /// while the old one drains.
+    pub fn bounded_with_ring_size(max_segments: usize) -> LCRQ<T, N> {
+        assert!(max_segments >= 2, "A bounded LCRQ needs at least 2 segments");
+        LCRQ::with_max_segments(max_segments as u64)
+    }
+
+    fn with_max_segments(max_segments: u64) -> LCRQ<T, N> {
+        let crq = Box::into_raw(Box::new(CRQ::with_ring_size()));
+        LCRQ { tail: AtomicPtr::new(crq), consumers: WaitQueue::new(), _pad_tail: [0; TAIL_PADDING],
+               head: AtomicPtr::new(crq), _pad_head: [0; 7],
+               epoch: AtomicU64::new(0), registry: Registry::new(), live_segments: AtomicU64::new(1),
+               linked_segments: AtomicU64::new(1), max_segments, producers: WaitQueue::new(),
+               wakers: WakerList::new(), counters: Counters::new(), _values: PhantomData }
+    }
+
+    pub fn dequeue(&self) -> DequeueResult<T> {
+        match self.dequeue_raw() {
+            DequeueResult::Value(raw) => DequeueResult::Value(unsafe { from_raw_value(raw) }),
+            DequeueResult::Empty      => DequeueResult::Empty,
+            DequeueResult::Closed     => DequeueResult::Closed,
+        }
+    }
+
+    /// Dequeue up to `max` values and append them to `values`, claiming slots for several
+    /// of them with each fetch-and-add. Returns the number of values dequeued, which is
+    /// zero if the queue is empty or closed.
+    pub fn drain_into(&self, values: &mut Vec<T>, max: usize) -> usize {
+        let mut buffer = [0; DRAIN_CHUNK];
+        let mut drained = 0;
+        while drained < max {
+            let chunk = &mut buffer[..cmp::min(max - drained, DRAIN_CHUNK)];
+            let dequeued = match self.dequeue_raw_batch(chunk) {
+                DequeueResult::Value(dequeued) => dequeued,
+                _ => break,
+            };
+            values.extend(chunk[..dequeued].iter().map(|&raw| unsafe { from_raw_value::<T>(raw) }));
+            drained += dequeued;
+        }
+        drained
     }
 
-    pub fn dequeue(&self) -> Option<u64> {
+    /// Dequeue a value, waiting for one to arrive if the queue is empty. Returns `None`
+    /// once the queue is closed and drained.
+    pub fn dequeue_blocking(&self) -> Option<T> {
+        self.consumers.wait_until(None, || self.dequeue_ready())
+            .expect("Waiting without a deadline only stops when ready")
+            .value()
+    }
+
+    /// Dequeue a value, waiting at most `timeout` for one to arrive. Returns `Empty` if
+    /// the time ran out. A timeout too long to be represented waits without a deadline.
+    pub fn dequeue_timeout(&self, timeout: Duration) -> DequeueResult<T> {
+        self.consumers.wait_until(Instant::now().checked_add(timeout), || self.dequeue_ready())
+            .unwrap_or(DequeueResult::Empty)
+    }
+
+    /// Dequeue a value asynchronously. The future resolves to `None` once the queue is
+    /// closed and drained.
+    pub fn recv_async(&self) -> RecvFuture<'_, T, N> {
+        RecvFuture { queue: self, key: None }
+    }
+
+    fn dequeue_ready(&self) -> Option<DequeueResult<T>> {
+        match self.dequeue() {
+            DequeueResult::Empty => None,
+            result => Some(result),
+        }
+    }
+
+    /// Enqueue a value, waiting for room if the queue is bounded and full
+    pub fn enqueue(&self, value: T) -> Result<(), Closed<T>> {
+        let raw = into_raw_value(value);
+        let enqueued = self.producers.wait_until(None, || match self.enqueue_raw(raw) {
+            Ok(())                          => Some(true),
+            Err(TryEnqueueError::Full(_))   => None,
+            Err(TryEnqueueError::Closed(_)) => Some(false),
+        }).expect("Waiting without a deadline only stops when ready");
+        if !enqueued {
+            return Err(Closed(unsafe { from_raw_value(raw) }));
+        }
+        self.notify_consumers(1);
+        Ok(())
+    }
+
+    /// Enqueue a value if there's room, without waiting
+    pub fn try_enqueue(&self, value: T) -> Result<(), TryEnqueueError<T>> {
+        self.enqueue_raw(into_raw_value(value)).map_err(|error| error.map(|raw| unsafe { from_raw_value(raw) }))?;
+        self.notify_consumers(1);
+        Ok(())
+    }
+
+    /// Enqueue clones of `values` in order. See `enqueue_iter`.
+    pub fn enqueue_batch(&self, values: &[T]) -> Result<(), Closed<Vec<T>>> where T: Clone {
+        self.enqueue_iter(values.iter().cloned())
+    }
+
+    /// Enqueue values in order, claiming slots for as many of them as possible with each
+    /// fetch-and-add, and waiting for room if the queue is bounded and full. Values from
+    /// other producers may end up in between. If the queue is closed, the values that
+    /// weren't enqueued are handed back.
+    pub fn enqueue_iter<I: IntoIterator<Item = T>>(&self, values: I) -> Result<(), Closed<Vec<T>>> {
+        let raw = values.into_iter().map(into_raw_value).collect::<Vec<_>>();
+        let mut enqueued = 0;
+        let closed = self.producers.wait_until(None, || {
+            let (result, count) = match self.enqueue_raw_batch(&raw[enqueued..]) {
+                Ok(())                              => (Some(false), raw.len() - enqueued),
+                Err(TryEnqueueError::Full(count))   => (None, count),
+                Err(TryEnqueueError::Closed(count)) => (Some(true), count),
+            };
+            // consumers need to hear about values enqueued before running out of room,
+            // or they might never make any
+            enqueued += count;
+            self.notify_consumers(count);
+            result
+        }).expect("Waiting without a deadline only stops when ready");
+        if closed {
+            return Err(Closed(raw[enqueued..].iter().map(|&raw| unsafe { from_raw_value(raw) }).collect()));
+        }
+        Ok(())
+    }
+
+    /// Wake up consumers after enqueueing `count` values
+    fn notify_consumers(&self, count: usize) {
+        match count {
+            0 => {}
+            1 => {
+                self.consumers.notify_one();
+                self.wakers.wake_one();
+            }
+            _ => {
+                self.consumers.notify_all();
+                self.wakers.wake_all();
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> LCRQ<T, N> {
+    /// Close the queue. Values that are already queued can still be dequeued, but any
+    /// later `enqueue` fails. Once the queue is drained, `dequeue` returns `Closed`.
+    pub fn close(&self) {
+        let _guard = self.pin();
         loop {
-            let crq : &CRQ = unsafe { mem::transmute(self.head) };
-            match crq.dequeue() {
-                Some(value) => { return Some(value); }
-                None => {
-                    if (*crq).next == ptr::null() {
-                        return None;
+            let crq_ptr = load_ptr(&self.tail);
+            let crq : &CRQ<N> = unsafe { &*crq_ptr };
+
+            let next = load_ptr(&crq.next);
+            if next == closed_marker() {
+                return;
+            }
+            if !next.is_null() {
+                compare_and_swap_ptr(&self.tail, crq_ptr, next);
+                continue;
+            }
+
+            // the ring is closed first, so its last values are settled before the marker is set
+            crq.close();
+            if compare_and_swap_ptr(&crq.next, ptr::null_mut(), closed_marker()) {
+                self.counters.add(Counter::RingsClosed, 1);
+                self.consumers.notify_all();
+                self.wakers.wake_all();
+                self.producers.notify_all();
+                return;
+            }
+        }
+    }
+
+    /// Number of values in the queue, estimated by adding up `CRQ::len_estimate` for every
+    /// CRQ from `head` on. The CRQs are visited one after the other, and none of them is
+    /// read at one instant, so under concurrency the result is not a snapshot: it can be
+    /// off by the number of enqueues and dequeues in flight, and counts slots that
+    /// enqueuers claimed but failed to fill until dequeuers have passed them. When no
+    /// operation is in flight, it's exact as long as every claimed slot was filled.
+    pub fn len_estimate(&self) -> usize {
+        let _guard = self.pin();
+        let mut len = 0;
+        let mut crq_ptr = load_ptr(&self.head);
+        while !crq_ptr.is_null() && crq_ptr != closed_marker() {
+            let crq : &CRQ<N> = unsafe { &*crq_ptr };
+            len += crq.len_estimate();
+            crq_ptr = load_ptr(&crq.next);
+        }
+        len
+    }
+
+    /// Whether the queue seems empty, with the same guarantees as `len_estimate`
+    pub fn is_empty(&self) -> bool {
+        self.len_estimate() == 0
+    }
+
+    /// Snapshot of the operation counters and gauges of this queue. The counters are read
+    /// one after the other, so the snapshot may miss operations in flight.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            enqueued: self.counters.get(Counter::Enqueued),
+            dequeued: self.counters.get(Counter::Dequeued),
+            rings_closed: self.counters.get(Counter::RingsClosed),
+            segments_allocated: self.counters.get(Counter::SegmentsAllocated),
+            depth: self.len_estimate() as u64,
+            segments: self.segment_count() as u64,
+        }
+    }
+
+    /// Number of CRQs from `head` to the end of the list
+    #[cfg(feature = "metrics")]
+    fn segment_count(&self) -> usize {
+        let _guard = self.pin();
+        let mut count = 0;
+        let mut crq_ptr = load_ptr(&self.head);
+        while !crq_ptr.is_null() && crq_ptr != closed_marker() {
+            count += 1;
+            crq_ptr = load_ptr(unsafe { &(*crq_ptr).next });
+        }
+        count
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let _guard = self.pin();
+        let crq : &CRQ<N> = unsafe { &*load_ptr(&self.tail) };
+        load_ptr(&crq.next) == closed_marker()
+    }
+
+    fn dequeue_raw(&self) -> DequeueResult<u64> {
+        let mut buffer = [0];
+        match self.dequeue_raw_batch(&mut buffer) {
+            DequeueResult::Value(_) => DequeueResult::Value(buffer[0]),
+            DequeueResult::Empty    => DequeueResult::Empty,
+            DequeueResult::Closed   => DequeueResult::Closed,
+        }
+    }
+
+    /// Dequeue raw values from a single CRQ into `buffer`, returning how many there were
+    fn dequeue_raw_batch(&self, buffer: &mut [u64]) -> DequeueResult<usize> {
+        let guard = self.pin();
+        loop {
+            let crq_ptr = load_ptr(&self.head);
+            let crq : &CRQ<N> = unsafe { &*crq_ptr };
+            match crq.dequeue_batch(buffer) {
+                0 => {
+                    let next = load_ptr(&crq.next);
+                    if next.is_null() {
+                        return DequeueResult::Empty;
                     }
-                    match crq.dequeue() {
-                        Some(value) => { return Some(value); }
-                        None => {
-                            compare_and_swap_crq_ptr(&self.head, unsafe { mem::transmute(crq) }, crq.next);
+                    match crq.dequeue_batch(buffer) {
+                        0 if next == closed_marker() => { return DequeueResult::Closed; }
+                        0 => {
+                            // never retire a CRQ that `tail` still points to
+                            if load_ptr(&self.tail) == crq_ptr {
+                                compare_and_swap_ptr(&self.tail, crq_ptr, next);
+                            }
+                            if compare_and_swap_ptr(&self.head, crq_ptr, next) {
+                                guard.retire(crq_ptr);
+                                self.release_segment();
+                            }
+                        }
+                        dequeued => {
+                            self.counters.add(Counter::Dequeued, dequeued);
+                            return DequeueResult::Value(dequeued);
                         }
                     }
                 }
+                dequeued => {
+                    self.counters.add(Counter::Dequeued, dequeued);
+                    return DequeueResult::Value(dequeued);
+                }
             }
         }
     }
 
-    pub fn enqueue(&self, value: u64) {
-        loop {
-            let crq : &CRQ = unsafe { mem::transmute(self.tail) };
+    /// Enqueue a raw value, or hand it back if the queue is closed or full
+    fn enqueue_raw(&self, value: u64) -> Result<(), TryEnqueueError<u64>> {
+        self.enqueue_raw_batch(slice::from_ref(&value)).map_err(|error| error.map(|_| value))
+    }
+
+    /// Enqueue raw values in order. If the queue is closed or full before all of them are
+    /// enqueued, the error holds the number of values that were.
+    fn enqueue_raw_batch(&self, values: &[u64]) -> Result<(), TryEnqueueError<usize>> {
+        let _guard = self.pin();
+        let mut enqueued = 0;
+        while enqueued < values.len() {
+            let crq_ptr = load_ptr(&self.tail);
+            let crq : &CRQ<N> = unsafe { &*crq_ptr };
 
-            if crq.next != ptr::null() {
-                compare_and_swap_crq_ptr(&self.tail, crq, crq.next);
+            let next = load_ptr(&crq.next);
+            if next == closed_marker() {
+                return Err(TryEnqueueError::Closed(enqueued));
+            }
+            if !next.is_null() {
+                compare_and_swap_ptr(&self.tail, crq_ptr, next);
                 continue;
             }
 
-            match crq.enqueue(value) {
-                Ok(_) => return,
-                Err(_) => { // queue closed
-                    let new_crq = CRQ::new();
-                    new_crq.enqueue(value).ok().expect("Enqueue expected to always work on an empty queue");
-                    let new_crq_ptr = untracked_pointer(new_crq);
-                    if compare_and_swap_crq_ptr(&crq.next, ptr::null(), new_crq_ptr) {
-                        compare_and_swap_crq_ptr(&self.tail, crq, new_crq_ptr);
-                        return;
-                    }
+            // a pointer is never NODE_VALUE_EMPTY
+            let filled = crq.enqueue_batch(&values[enqueued..]).expect("Raw values are never reserved");
+            self.counters.add(Counter::Enqueued, filled);
+            enqueued += filled;
+            if enqueued == values.len() {
+                break;
+            }
+
+            // the CRQ closed before taking all the values, so the rest spills into a new one.
+            // The new CRQ is counted before it's linked, so dequeuers can't retire it before
+            // it's counted, and the count never goes below the real one.
+            if fetch_and_add(&self.linked_segments, 1) >= self.max_segments {
+                self.cancel_reservation();
+                // appending would go over the limit, unless the queue was closed meanwhile
+                if load_ptr(&crq.next) == closed_marker() {
+                    return Err(TryEnqueueError::Closed(enqueued));
                 }
+                return Err(TryEnqueueError::Full(enqueued));
             }
+            let new_crq = CRQ::with_ring_size();
+            let filled = new_crq.enqueue_batch(&values[enqueued..]).expect("Raw values are never reserved");
+            let new_crq_ptr = self.allocate_segment(new_crq);
+            if compare_and_swap_ptr(&crq.next, ptr::null_mut(), new_crq_ptr) {
+                compare_and_swap_ptr(&self.tail, crq_ptr, new_crq_ptr);
+                self.counters.add(Counter::RingsClosed, 1);
+                self.counters.add(Counter::Enqueued, filled);
+                enqueued += filled;
+                continue;
+            }
+            // someone else appended a CRQ or closed the queue first, and ours was never shared
+            self.cancel_reservation();
+            unsafe { self.free_segment(new_crq_ptr); }
+        }
+        Ok(())
+    }
+
+    /// Give back a place in `linked_segments` for a CRQ that was unlinked, waking up
+    /// producers waiting for room
+    fn release_segment(&self) {
+        self.linked_segments.fetch_sub(1, Ordering::SeqCst);
+        self.producers.notify_all();
+    }
+
+    /// Give back a place in `linked_segments` that was reserved but not used. Producers
+    /// only need waking if that leaves room. A producer that found the queue full gives
+    /// back a place beyond the limit, and waking producers then would wake itself.
+    fn cancel_reservation(&self) {
+        if self.linked_segments.fetch_sub(1, Ordering::SeqCst) <= self.max_segments {
+            self.producers.notify_all();
+        }
+    }
+
+    fn allocate_segment(&self, crq: CRQ<N>) -> *mut CRQ<N> {
+        stats::record(Event::SegmentAllocated);
+        self.counters.add(Counter::SegmentsAllocated, 1);
+        fetch_and_add(&self.live_segments, 1);
+        Box::into_raw(Box::new(crq))
+    }
+
+    /// Free a CRQ previously returned by `allocate_segment`. The caller must guarantee
+    /// that no other thread can still reach it.
+    unsafe fn free_segment(&self, crq: *mut CRQ<N>) {
+        drop(Box::from_raw(crq));
+        self.live_segments.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Pin the current epoch in this thread's participant record
+    fn pin<'a>(&'a self) -> Guard<'a, T, N> {
+        let (participant, handle) = match HANDLES.try_with(|handles| handles.borrow_mut().participant(&self.registry)) {
+            Ok(participant) => (participant, None),
+            Err(_) => {
+                // the thread is exiting and its cache is gone, so borrow a participant instead
+                let handle = Registry::claim(&self.registry);
+                (handle.participant, Some(handle))
+            }
+        };
+        // the registry lives at least as long as the queue
+        let participant = unsafe { &*participant };
+
+        let guards = participant.guards.get();
+        if guards == 0 {
+            // the store is sequentially consistent, so the pin is visible before we read any CRQ
+            store(participant.epoch.ref_combined(), FlagAndU63::new(true, load(&self.epoch)).combined());
+        }
+        participant.guards.set(guards + 1);
+        Guard { queue: self, participant, _handle: handle }
+    }
+
+    /// Advance the global epoch if every pinned participant has observed it. Returns the
+    /// current global epoch.
+    fn try_advance_epoch(&self) -> u64 {
+        let global_epoch = load(&self.epoch);
+
+        let mut current = load_ptr(&self.registry.participants);
+        while !current.is_null() {
+            let participant = unsafe { &*current };
+            let (pinned, epoch) = participant.epoch.flag_and_value();
+            if pinned && epoch != global_epoch {
+                return global_epoch;
+            }
+            current = participant.next;
+        }
+
+        if compare_and_swap(&self.epoch, global_epoch, global_epoch + 1) {
+            global_epoch + 1
+        } else {
+            load(&self.epoch)
+        }
+    }
+}
+
+/// Future returned by `LCRQ::recv_async`
+pub struct RecvFuture<'a, T, const N: usize> {
+    queue: &'a LCRQ<T, N>,
+    key: Option<u64>, // registration in `queue.wakers`, if any
+}
+
+impl<'a, T, const N: usize> Future for RecvFuture<'a, T, N> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let result = match this.queue.dequeue() {
+            DequeueResult::Empty => {
+                // registering before polling again means an enqueue after the poll wakes us
+                this.queue.wakers.register(&mut this.key, cx.waker());
+                match this.queue.dequeue() {
+                    DequeueResult::Empty => return Poll::Pending,
+                    result => result,
+                }
+            }
+            result => result,
+        };
+        if let Some(key) = this.key.take() {
+            this.queue.wakers.deregister(key);
+        }
+        Poll::Ready(result.value())
+    }
+}
+
+impl<'a, T, const N: usize> Drop for RecvFuture<'a, T, N> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if !self.queue.wakers.deregister(key) {
+                // woken for a value that was never taken, so pass the wakeup on
+                self.queue.wakers.wake_one();
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for LCRQ<T, N> {
+    fn drop(&mut self) {
+        while let DequeueResult::Value(raw) = self.dequeue_raw() {
+            drop(unsafe { from_raw_value::<T>(raw) });
+        }
+
+        // other threads drop their handles when they next pin a new queue, or exit
+        self.registry.queue_dropped.store(true, Ordering::SeqCst);
+        let _ = HANDLES.try_with(|handles| {
+            if let Ok(mut handles) = handles.try_borrow_mut() {
+                handles.remove(&self.registry);
+            }
+        });
+
+        // nobody else can be pinned anymore, so all retired CRQs can be freed right away
+        let mut current = load_ptr(&self.registry.participants);
+        while !current.is_null() {
+            let participant = unsafe { &*current };
+            for (_, crq) in mem::take(unsafe { &mut *participant.garbage.get() }) {
+                unsafe { self.free_segment(crq as *mut CRQ<N>); }
+            }
+            current = participant.next;
+        }
+
+        let mut crq = *self.head.get_mut();
+        while !crq.is_null() && crq != closed_marker() {
+            let next = unsafe { load_ptr(&(*crq).next) };
+            unsafe { self.free_segment(crq); }
+            crq = next;
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::thread::{ spawn, JoinHandle };
+    use std::thread::{ self, spawn, JoinHandle };
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use std::cmp::max;
     use super::*;
+    use std::task::Poll;
+    use super::DequeueResult::{Value, Empty};
     use crq::RING_SIZE;
+    use executor::{ThreadWaker, block_on, poll_once};
+    use linearizability::History;
+    use stress;
 
     #[test]
     fn test_enqueue_ring_plus_one() {
         let lcrq = LCRQ::new();
         for i in 0..RING_SIZE+1 {
-            lcrq.enqueue(100 + i as u64);
+            assert!(lcrq.enqueue(100 + i as u64).is_ok());
         }
     }
 
@@ -100,10 +831,10 @@ mod test {
     fn test_enqueue_front_load() {
         let lcrq = LCRQ::new();
         for i in 0..RING_SIZE*10 {
-            lcrq.enqueue(100 + i as u64);
+            assert!(lcrq.enqueue(100 + i as u64).is_ok());
         }
         for i in 0..RING_SIZE*10 {
-            assert!(lcrq.dequeue() == Some(100 + i as u64));
+            assert_eq!(lcrq.dequeue(), Value(100 + i as u64));
         }
     }
 
@@ -116,7 +847,7 @@ mod test {
 
         let producer = spawn(move || {
             for i in 0..RING_SIZE*100 {
-                prod_lcrq.enqueue(100 + i as u64);
+                assert!(prod_lcrq.enqueue(100 + i as u64).is_ok());
             }
         });
 
@@ -124,8 +855,8 @@ mod test {
             for i in 0..RING_SIZE*100 {
                 loop {
                     match cons_lcrq.dequeue() {
-                        Some(number) => { assert_eq!(number, 100 + i as u64); break },
-                        None => { /* spin */ },
+                        Value(number) => { assert_eq!(number, 100 + i as u64); break },
+                        _ => { /* spin */ },
                     }
                 }
             }
@@ -138,31 +869,827 @@ mod test {
     #[test]
     fn multi_producer_single_consumer() {
         let lcrq = Arc::new(LCRQ::new());
+        let history = History::new();
 
-        let producer_1 = start_producer(lcrq.clone(), 100000, 100100);
-        let producer_2 = start_producer(lcrq.clone(), 100100, 100200);
+        let producers = [(100000, 100100), (100100, 100200)].iter().enumerate().map(|(p, &(start, end))| {
+            let prod_lcrq = lcrq.clone();
+            let mut recorder = history.recorder(p);
+            spawn(move || {
+                for i in start..end {
+                    assert!(recorder.enqueue(&prod_lcrq, i));
+                }
+            })
+        }).collect::<Vec<_>>();
 
         let cons_lcrq = lcrq.clone();
+        let mut recorder = history.recorder(2);
         let consumer = spawn(move || {
             for _ in 0..200 {
+                let number = recorder.dequeue_spinning(&cons_lcrq);
+                assert!(number >= 100000);
+                assert!(number < 100200);
+            }
+        });
+
+        for producer in producers {
+            assert!(producer.join().is_ok());
+        }
+        assert!(consumer.join().is_ok());
+        history.assert_linearizable();
+    }
+
+    #[test]
+    fn test_per_producer_fifo() {
+        // producers, consumers and segment size, from a single pair to more consumers than
+        // producers, with segments small enough to hop between them all the time
+        stress::run::<RING_SIZE>(1, 1, 20_000);
+        stress::run::<RING_SIZE>(4, 1, 5_000);
+        stress::run::<4>(4, 4, 5_000);
+        stress::run::<2>(2, 6, 5_000);
+        stress::run::<64>(6, 2, 5_000);
+    }
+
+    #[test]
+    fn test_participants_are_reused() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::with_ring_size());
+        for _ in 0..3 {
+            let threads = (0..4).map(|_| {
+                let lcrq = lcrq.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        assert!(lcrq.enqueue(i).is_ok());
+                        assert!(lcrq.dequeue().value().is_some());
+                    }
+                })
+            }).collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+
+        // every thread owns one record, and exited threads hand theirs over to new ones
+        let participants = participant_count(&lcrq);
+        assert!(participants <= 4, "{} participant records for 4 threads at a time", participants);
+    }
+
+    #[test]
+    fn test_thread_alternates_between_queues() {
+        // runs on its own thread, so only these queues are in its cache
+        spawn(|| {
+            let mut queues = (0..32).map(|_| LCRQ::<u64, 4>::with_ring_size()).collect::<Vec<_>>();
+            for round in 0..10 {
+                for lcrq in &queues {
+                    assert!(lcrq.enqueue(round).is_ok());
+                }
+            }
+            for lcrq in &queues {
+                for round in 0..10 {
+                    assert_eq!(lcrq.dequeue(), Value(round));
+                }
+                assert_eq!(participant_count(lcrq), 1);
+            }
+            HANDLES.with(|handles| assert_eq!(handles.borrow().by_registry.len(), 32));
+
+            // dropping a queue drops this thread's handle right away
+            queues.truncate(16);
+            HANDLES.with(|handles| assert_eq!(handles.borrow().by_registry.len(), 16));
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn retired_segments_are_freed() {
+        let lcrq = LCRQ::new();
+        for round in 0..1000 {
+            for i in 0..RING_SIZE*3 {
+                assert!(lcrq.enqueue(round * 10000 + i as u64).is_ok());
+            }
+            for i in 0..RING_SIZE*3 {
+                assert_eq!(lcrq.dequeue(), Value(round * 10000 + i as u64));
+            }
+            assert!(load(&lcrq.live_segments) <= 4, "{} segments alive after round {}", load(&lcrq.live_segments), round);
+        }
+    }
+
+    #[test]
+    fn soak_memory_stays_flat() {
+        let lcrq = Arc::new(LCRQ::new());
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let count = RING_SIZE * 500;
+
+        let prod_lcrq = lcrq.clone();
+        let prod_consumed = consumed.clone();
+        let producer = spawn(move || {
+            for i in 0..count {
+                // stay at most two rings ahead, so only retired segments can make memory grow
+                while i - prod_consumed.load(Ordering::SeqCst) > RING_SIZE*2 { /* spin */ }
+                assert!(prod_lcrq.enqueue(i as u64).is_ok());
+            }
+        });
+
+        let mut max_live_segments = 0;
+        for i in 0..count {
+            loop {
+                match lcrq.dequeue() {
+                    Value(number) => { assert_eq!(number, i as u64); break },
+                    _ => { /* spin */ },
+                }
+            }
+            consumed.store(i + 1, Ordering::SeqCst);
+            max_live_segments = max(max_live_segments, load(&lcrq.live_segments));
+        }
+
+        assert!(producer.join().is_ok());
+        assert!(max_live_segments <= 8, "{} segments alive at once", max_live_segments);
+    }
+
+    #[test]
+    fn owned_values() {
+        let lcrq = LCRQ::new();
+        for i in 0..RING_SIZE*3 {
+            assert!(lcrq.enqueue(format!("value {}", i)).is_ok());
+        }
+        for i in 0..RING_SIZE*3 {
+            assert_eq!(lcrq.dequeue(), Value(format!("value {}", i)));
+        }
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn test_ring_sizes() {
+        fn front_load<const N: usize>() {
+            let lcrq = LCRQ::<u64, N>::with_ring_size();
+            for i in 0..N*10 {
+                assert!(lcrq.enqueue(i as u64).is_ok());
+            }
+            for i in 0..N*10 {
+                assert_eq!(lcrq.dequeue(), Value(i as u64));
+            }
+            assert_eq!(lcrq.dequeue(), Empty);
+        }
+
+        front_load::<1>();
+        front_load::<2>();
+        front_load::<16>();
+        front_load::<65536>();
+    }
+
+    #[test]
+    fn test_tiny_ring_multithreaded() {
+        let lcrq = Arc::new(LCRQ::<u64, 2>::with_ring_size());
+
+        let producer = start_producer(lcrq.clone(), 0, 10000);
+        for i in 0..10000 {
+            loop {
+                match lcrq.dequeue() {
+                    Value(number) => { assert_eq!(number, i); break },
+                    _ => { /* spin */ },
+                }
+            }
+        }
+
+        assert!(producer.join().is_ok());
+    }
+
+    #[test]
+    fn test_producer_progress_against_hungry_consumers() {
+        // consumers spinning on a nearly empty queue keep taking the slots the producer is
+        // about to write to. Starving enqueues close their ring and move on to a new one.
+        let lcrq = Arc::new(LCRQ::<u64, 4>::with_ring_size());
+        let count = 20000;
+
+        let consumers = (0..4).map(|_| {
+            let lcrq = lcrq.clone();
+            spawn(move || {
+                let mut received = vec![];
                 loop {
-                    match cons_lcrq.dequeue() {
-                        Some(number) => { assert!(number >= 100000); assert!(number < 100200); break },
-                        None => { /* spin */ },
+                    match lcrq.dequeue() {
+                        Value(number) if number == u64::MAX => return received,
+                        Value(number) => received.push(number),
+                        _ => { /* spin */ },
                     }
                 }
+            })
+        }).collect::<Vec<_>>();
+
+        let (done, finished) = channel();
+        let prod_lcrq = lcrq.clone();
+        spawn(move || {
+            for i in 0..count {
+                assert!(prod_lcrq.enqueue(i).is_ok());
+            }
+            for _ in 0..4 {
+                assert!(prod_lcrq.enqueue(u64::MAX).is_ok());
             }
+            done.send(()).unwrap();
         });
+        finished.recv_timeout(Duration::from_secs(60)).expect("producer made no progress");
 
-        assert!(producer_1.join().is_ok());
-        assert!(producer_2.join().is_ok());
-        assert!(consumer.join().is_ok());
+        let mut all_received = vec![];
+        for consumer in consumers {
+            let received = consumer.join().unwrap();
+            // a single producer, so every consumer sees its values in order
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+            all_received.extend(received);
+        }
+        all_received.sort();
+        assert_eq!(all_received, (0..count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_close_empty() {
+        let lcrq = LCRQ::new();
+        assert!(!lcrq.is_closed());
+        assert_eq!(lcrq.dequeue(), Empty);
+
+        lcrq.close();
+        assert!(lcrq.is_closed());
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+        assert_eq!(lcrq.enqueue(1), Err(Closed(1)));
+
+        lcrq.close();
+        assert!(lcrq.is_closed());
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_close_drains_queued_values() {
+        let lcrq = LCRQ::new();
+        for i in 0..RING_SIZE*3 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+
+        lcrq.close();
+        assert_eq!(lcrq.enqueue(RING_SIZE*3), Err(Closed(RING_SIZE*3)));
+        for i in 0..RING_SIZE*3 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_close_full_ring() {
+        // the last ring is already closed for being full, with no CRQ after it yet
+        let lcrq = LCRQ::<usize, 4>::with_ring_size();
+        for i in 0..4 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+        lcrq.close();
+        assert_eq!(lcrq.enqueue(4), Err(Closed(4)));
+        for i in 0..4 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_close_multithreaded() {
+        let lcrq = Arc::new(LCRQ::<(u64, u64), 16>::with_ring_size());
+
+        // every producer enqueues until the queue closes, and reports how far it got
+        let producers = (0..4).map(|producer| {
+            let lcrq = lcrq.clone();
+            spawn(move || {
+                let mut sequence = 0;
+                while lcrq.enqueue((producer, sequence)).is_ok() {
+                    sequence += 1;
+                }
+                sequence
+            })
+        }).collect::<Vec<_>>();
+
+        let consumers = (0..2).map(|_| {
+            let lcrq = lcrq.clone();
+            spawn(move || {
+                let mut received = vec![];
+                loop {
+                    match lcrq.dequeue() {
+                        Value(value) => received.push(value),
+                        Empty => { /* spin */ },
+                        DequeueResult::Closed => return received,
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(50));
+        lcrq.close();
+
+        let enqueued = producers.into_iter().map(|producer| producer.join().unwrap()).collect::<Vec<_>>();
+        let mut received = vec![vec![]; 4];
+        for consumer in consumers {
+            let mut last_seen = [None; 4];
+            for (producer, sequence) in consumer.join().unwrap() {
+                // values from the same producer arrive in order
+                assert!(last_seen[producer as usize] < Some(sequence));
+                last_seen[producer as usize] = Some(sequence);
+                received[producer as usize].push(sequence);
+            }
+        }
+
+        for producer in 0..4 {
+            received[producer].sort();
+            assert_eq!(received[producer], (0..enqueued[producer]).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_dequeue_blocking_waits_for_producer() {
+        let lcrq = Arc::new(LCRQ::new());
+        let consumer_lcrq = lcrq.clone();
+        let consumer = spawn(move || consumer_lcrq.dequeue_blocking());
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(lcrq.enqueue(42).is_ok());
+        assert_eq!(consumer.join().unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_dequeue_timeout() {
+        let lcrq = LCRQ::new();
+        assert_eq!(lcrq.dequeue_timeout(Duration::from_millis(10)), Empty);
+        assert!(lcrq.enqueue(1).is_ok());
+        assert_eq!(lcrq.dequeue_timeout(Duration::from_millis(10)), Value(1));
+        lcrq.close();
+        assert_eq!(lcrq.dequeue_timeout(Duration::from_millis(10)), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_dequeue_timeout_too_long_for_instant() {
+        let lcrq = Arc::new(LCRQ::new());
+        assert!(lcrq.enqueue(1).is_ok());
+        assert_eq!(lcrq.dequeue_timeout(Duration::MAX), Value(1));
+
+        // without a deadline, it waits until a value arrives
+        let producer = start_producer(lcrq.clone(), 2, 3);
+        assert_eq!(lcrq.dequeue_timeout(Duration::MAX), Value(2));
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_wakes_blocked_consumers() {
+        let lcrq = Arc::new(LCRQ::<u64>::new());
+        let consumers = (0..4).map(|_| {
+            let lcrq = lcrq.clone();
+            spawn(move || lcrq.dequeue_blocking())
+        }).collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(50));
+        lcrq.close();
+        for consumer in consumers {
+            assert_eq!(consumer.join().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_dequeue_blocking_multithreaded() {
+        stress::run::<16>(4, 4, 10_000);
+    }
+
+    #[test]
+    fn test_recv_async_ready() {
+        let lcrq = LCRQ::new();
+        assert!(lcrq.enqueue(1).is_ok());
+        assert_eq!(block_on(lcrq.recv_async()), Some(1));
+        lcrq.close();
+        assert_eq!(block_on(lcrq.recv_async()), None);
+    }
+
+    #[test]
+    fn test_recv_async_woken_by_enqueue() {
+        let lcrq = LCRQ::new();
+        let waker = ThreadWaker::new();
+        let mut future = lcrq.recv_async();
+        assert_eq!(poll_once(&mut future, &waker), Poll::Pending);
+        assert_eq!(lcrq.wakers.registered(), 1);
+
+        assert!(lcrq.enqueue(7).is_ok());
+        assert!(waker.take_woken());
+        assert_eq!(poll_once(&mut future, &waker), Poll::Ready(Some(7)));
+        assert_eq!(lcrq.wakers.registered(), 0);
+    }
+
+    #[test]
+    fn test_recv_async_woken_by_close() {
+        let lcrq = LCRQ::<u64>::new();
+        let waker = ThreadWaker::new();
+        let mut future = lcrq.recv_async();
+        assert_eq!(poll_once(&mut future, &waker), Poll::Pending);
+
+        lcrq.close();
+        assert!(waker.take_woken());
+        assert_eq!(poll_once(&mut future, &waker), Poll::Ready(None));
+    }
+
+    #[test]
+    fn test_recv_async_drop_deregisters() {
+        let lcrq = LCRQ::new();
+        let waker = ThreadWaker::new();
+        let mut future = lcrq.recv_async();
+        assert_eq!(poll_once(&mut future, &waker), Poll::Pending);
+        assert_eq!(poll_once(&mut future, &waker), Poll::Pending);
+        assert_eq!(lcrq.wakers.registered(), 1);
+
+        drop(future);
+        assert_eq!(lcrq.wakers.registered(), 0);
+        assert!(lcrq.enqueue(1).is_ok());
+        assert!(!waker.take_woken());
+    }
+
+    #[test]
+    fn test_recv_async_dropped_after_wakeup_passes_it_on() {
+        let lcrq = LCRQ::new();
+        let first_waker = ThreadWaker::new();
+        let second_waker = ThreadWaker::new();
+        let mut first = lcrq.recv_async();
+        let mut second = lcrq.recv_async();
+        assert_eq!(poll_once(&mut first, &first_waker), Poll::Pending);
+        assert_eq!(poll_once(&mut second, &second_waker), Poll::Pending);
+
+        assert!(lcrq.enqueue(1).is_ok());
+        assert!(first_waker.take_woken());
+        assert!(!second_waker.take_woken());
+
+        drop(first);
+        assert!(second_waker.take_woken());
+        assert_eq!(poll_once(&mut second, &second_waker), Poll::Ready(Some(1)));
+    }
+
+    #[test]
+    fn test_recv_async_multithreaded() {
+        stress::run_with(4, 4, 10_000, LCRQ::<u64, 16>::with_ring_size, |lcrq| {
+            let mut received = Vec::new();
+            while let Some(value) = block_on(lcrq.recv_async()) {
+                received.push(value);
+            }
+            received
+        });
+    }
+
+    #[test]
+    fn test_bounded_try_enqueue_full() {
+        let lcrq = LCRQ::<u64, 4>::bounded_with_ring_size(2);
+        for i in 0..8 {
+            assert_eq!(lcrq.try_enqueue(i), Ok(()));
+        }
+        assert_eq!(lcrq.try_enqueue(8), Err(TryEnqueueError::Full(8)));
+
+        // draining the first CRQ makes room once the head moves past it
+        for i in 0..4 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), Value(4));
+        assert_eq!(lcrq.try_enqueue(8), Ok(()));
+        for i in 5..9 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn test_bounded_try_enqueue_closed() {
+        let lcrq = LCRQ::<u64, 4>::bounded_with_ring_size(2);
+        for i in 0..8 {
+            assert_eq!(lcrq.try_enqueue(i), Ok(()));
+        }
+        lcrq.close();
+        assert_eq!(lcrq.try_enqueue(8), Err(TryEnqueueError::Closed(8)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bounded_needs_two_segments() {
+        LCRQ::<u64>::bounded(1);
+    }
+
+    #[test]
+    fn test_bounded_enqueue_waits_for_room() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::bounded_with_ring_size(2));
+        for i in 0..8 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+
+        let (sender, enqueued) = channel();
+        let producer_lcrq = lcrq.clone();
+        let producer = spawn(move || {
+            assert!(producer_lcrq.enqueue(8).is_ok());
+            sender.send(()).unwrap();
+        });
+
+        assert!(enqueued.recv_timeout(Duration::from_millis(50)).is_err());
+        for i in 0..5 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        enqueued.recv_timeout(Duration::from_secs(10)).expect("The producer never got room");
+        producer.join().unwrap();
+        for i in 5..9 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+    }
+
+    #[test]
+    fn test_bounded_blocked_producer_sleeps() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::bounded_with_ring_size(2));
+        for i in 0..8 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+
+        // polls the way `enqueue` does, counting every poll
+        let polls = Arc::new(AtomicUsize::new(0));
+        let producer = {
+            let lcrq = lcrq.clone();
+            let polls = polls.clone();
+            spawn(move || {
+                lcrq.producers.wait_until(None, || {
+                    polls.fetch_add(1, Ordering::SeqCst);
+                    match lcrq.try_enqueue(8) {
+                        Err(TryEnqueueError::Full(_)) => None,
+                        result => Some(result),
+                    }
+                }).unwrap()
+            })
+        };
+
+        // failing to reserve a segment must not wake the producer up to poll again
+        thread::sleep(Duration::from_millis(100));
+        let asleep = polls.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        let polled = polls.load(Ordering::SeqCst) - asleep;
+        assert!(polled <= 1, "The blocked producer polled {} times while the queue stayed full", polled);
+
+        for i in 0..5 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(producer.join().unwrap(), Ok(()));
+        for i in 5..9 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+    }
+
+    #[test]
+    fn test_close_wakes_blocked_producers() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::bounded_with_ring_size(2));
+        for i in 0..8 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+        let producers = (0..4).map(|i| {
+            let lcrq = lcrq.clone();
+            spawn(move || lcrq.enqueue(100 + i))
+        }).collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(50));
+        lcrq.close();
+        for (i, producer) in producers.into_iter().enumerate() {
+            assert_eq!(producer.join().unwrap(), Err(Closed(100 + i as u64)));
+        }
+    }
+
+    #[test]
+    fn test_bounded_multithreaded() {
+        const MAX_SEGMENTS: usize = 3;
+        const PRODUCERS: usize = 4;
+        stress::run_with(PRODUCERS, 2, 2_500, || LCRQ::<u64, 16>::bounded_with_ring_size(MAX_SEGMENTS), |lcrq| {
+            let mut received = Vec::new();
+            while let Some(value) = lcrq.dequeue_blocking() {
+                // a rejected reservation briefly counts too, at most one per producer
+                assert!(load(&lcrq.linked_segments) <= (MAX_SEGMENTS + PRODUCERS) as u64);
+                received.push(value);
+            }
+            received
+        });
+    }
+
+    #[test]
+    fn test_enqueue_batch_spills_into_new_segments() {
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        assert!(lcrq.enqueue(0).is_ok());
+        assert!(lcrq.enqueue_batch(&(1..11).collect::<Vec<_>>()).is_ok());
+        assert_eq!(load(&lcrq.live_segments), 3);
+        for i in 0..11 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn test_enqueue_batch_much_larger_than_ring() {
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        assert!(lcrq.enqueue_batch(&(0..4_000).collect::<Vec<_>>()).is_ok());
+        assert_eq!(load(&lcrq.live_segments), 1_000);
+        let mut values = Vec::new();
+        assert_eq!(lcrq.drain_into(&mut values, usize::MAX), 4_000);
+        assert_eq!(values, (0..4_000).collect::<Vec<_>>());
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn test_enqueue_iter_closed() {
+        let lcrq = LCRQ::new();
+        assert!(lcrq.enqueue_iter(Vec::new()).is_ok());
+        lcrq.close();
+        assert_eq!(lcrq.enqueue_iter(vec![1, 2, 3]), Err(Closed(vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn test_bounded_enqueue_iter_waits_for_room() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::bounded_with_ring_size(2));
+        let producer_lcrq = lcrq.clone();
+        let producer = spawn(move || producer_lcrq.enqueue_iter(0..20));
+        for i in 0..20 {
+            assert_eq!(lcrq.dequeue_blocking(), Some(i));
+        }
+        assert_eq!(producer.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn test_bounded_enqueue_iter_closed_partway() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::bounded_with_ring_size(2));
+        let producer_lcrq = lcrq.clone();
+        let producer = spawn(move || producer_lcrq.enqueue_iter(0..12));
+
+        thread::sleep(Duration::from_millis(50));
+        lcrq.close();
+        assert_eq!(producer.join().unwrap(), Err(Closed((8..12).collect())));
+        for i in 0..8 {
+            assert_eq!(lcrq.dequeue(), Value(i));
+        }
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_enqueue_batch_multithreaded() {
+        // a single consumer sees every producer's values in the order they were batched
+        let lcrq = Arc::new(LCRQ::<(usize, u64), 16>::with_ring_size());
+        let per_producer = 10_000;
+        let producers = (0..4).map(|p| {
+            let lcrq = lcrq.clone();
+            spawn(move || {
+                let values = (0..per_producer).map(|i| (p, i)).collect::<Vec<_>>();
+                for batch in values.chunks(37) {
+                    assert!(lcrq.enqueue_batch(batch).is_ok());
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let mut next = [0; 4];
+        for _ in 0..4 * per_producer {
+            let (p, i) = lcrq.dequeue_blocking().unwrap();
+            assert_eq!(i, next[p]);
+            next[p] += 1;
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn test_drain_into() {
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        for i in 0..10 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+
+        let mut values = vec![100];
+        assert_eq!(lcrq.drain_into(&mut values, 3), 3);
+        assert_eq!(values, [100, 0, 1, 2]);
+        assert_eq!(lcrq.drain_into(&mut values, 0), 0);
+
+        // drains across CRQs
+        values.clear();
+        assert_eq!(lcrq.drain_into(&mut values, 100), 7);
+        assert_eq!(values, (3..10).collect::<Vec<_>>());
+        assert_eq!(lcrq.drain_into(&mut values, 100), 0);
+
+        lcrq.close();
+        assert_eq!(lcrq.drain_into(&mut values, 100), 0);
+        assert_eq!(lcrq.dequeue(), DequeueResult::Closed);
+    }
+
+    #[test]
+    fn test_drain_into_multithreaded() {
+        stress::run_with(4, 2, 10_000, LCRQ::<u64, 16>::with_ring_size, |lcrq| {
+            let mut received = Vec::new();
+            loop {
+                let closed = lcrq.is_closed();
+                if lcrq.drain_into(&mut received, 100) == 0 && closed {
+                    return received;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_len_estimate() {
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        assert_eq!(lcrq.len_estimate(), 0);
+        assert!(lcrq.is_empty());
+        for i in 0..6 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+        assert_eq!(lcrq.len_estimate(), 6);
+
+        // the enqueue that found the first CRQ full claimed a slot it never filled, which
+        // counts once the CRQ has fewer values than slots, until it's drained
+        for _ in 0..3 {
+            assert!(lcrq.dequeue().value().is_some());
+        }
+        assert_eq!(lcrq.len_estimate(), 3 + 1);
+        assert!(lcrq.dequeue().value().is_some());
+        assert_eq!(lcrq.len_estimate(), 2 + 1);
+        assert!(lcrq.dequeue().value().is_some());
+        assert_eq!(lcrq.len_estimate(), 1);
+
+        lcrq.close();
+        assert_eq!(lcrq.len_estimate(), 1);
+        while lcrq.dequeue().value().is_some() {}
+        assert_eq!(lcrq.len_estimate(), 0);
+        assert!(lcrq.is_empty());
+    }
+
+    #[test]
+    fn test_len_estimate_multithreaded() {
+        let lcrq = Arc::new(LCRQ::<u64, 16>::with_ring_size());
+        let per_producer = 10_000;
+        // the values from `tag(p, 0)` up to `tag(p, per_producer)` are producer p's tagged values
+        let producers = (0..4).map(|p| start_producer(lcrq.clone(), stress::tag(p, 0), stress::tag(p, per_producer))).collect::<Vec<_>>();
+        while producers.iter().any(|producer| !producer.is_finished()) {
+            assert!(lcrq.len_estimate() <= 4 * per_producer as usize);
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        // slots lost by starving enqueuers may be counted too, but no value is missed
+        assert!(lcrq.len_estimate() >= 4 * per_producer as usize);
+        let mut received = Vec::new();
+        while let Some(value) = lcrq.dequeue().value() {
+            received.push(value);
+        }
+        stress::verify(4, per_producer, &[received]);
+        assert!(lcrq.is_empty());
+    }
+
+    #[test]
+    fn test_enqueue_empty_sentinel_value() {
+        let lcrq = LCRQ::new();
+        assert!(lcrq.enqueue(1).is_ok());
+        assert!(lcrq.enqueue(u64::MAX).is_ok());
+        assert!(lcrq.enqueue(2).is_ok());
+        assert_eq!(lcrq.dequeue(), Value(1));
+        assert_eq!(lcrq.dequeue(), Value(u64::MAX));
+        assert_eq!(lcrq.dequeue(), Value(2));
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    #[test]
+    fn zero_sized_values() {
+        let lcrq = LCRQ::new();
+        for _ in 0..RING_SIZE+1 {
+            assert!(lcrq.enqueue(()).is_ok());
+        }
+        for _ in 0..RING_SIZE+1 {
+            assert_eq!(lcrq.dequeue(), Value(()));
+        }
+        assert_eq!(lcrq.dequeue(), Empty);
+    }
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let lcrq = LCRQ::new();
+        for _ in 0..RING_SIZE*3 {
+            assert!(lcrq.enqueue(DropCounter(drops.clone())).is_ok());
+        }
+        for _ in 0..RING_SIZE {
+            drop(lcrq.dequeue());
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), RING_SIZE);
+
+        drop(lcrq);
+        assert_eq!(drops.load(Ordering::SeqCst), RING_SIZE*3);
+    }
+
+    fn participant_count<T, const N: usize>(lcrq: &LCRQ<T, N>) -> usize {
+        let mut count = 0;
+        let mut current = load_ptr(&lcrq.registry.participants);
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { (*current).next };
+        }
+        count
     }
 
-    fn start_producer(queue: Arc<LCRQ>, start: u64, end: u64) -> JoinHandle<()> {
+    fn start_producer<const N: usize>(queue: Arc<LCRQ<u64, N>>, start: u64, end: u64) -> JoinHandle<()> {
         spawn(move || {
             for i in start..end {
-                queue.enqueue(i);
+                assert!(queue.enqueue(i).is_ok());
             }
         })
     }
@@ -1,7 +1,29 @@
-#![feature(asm)]
+#[cfg(all(target_os = "linux", feature = "futex"))]
+extern crate libc;
 
 pub mod crq;
 pub mod lcrq;
+pub mod channel;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "model")]
+pub mod model;
 pub mod flag_and_u63; // TODO: Using `pub` only to suppress unused warnings
 pub mod node; // TODO: Using `pub` only to suppress unused warnings
 mod atomics;
+mod wait;
+mod stats;
+mod counters;
+#[cfg(test)]
+mod executor;
+#[cfg(test)]
+mod linearizability;
+#[cfg(test)]
+mod differential;
+#[cfg(test)]
+mod stress;
+
+pub use atomics::{CasBackend, cas_backend, select_cas_backend};
+pub use channel::channel;
+#[cfg(feature = "stats")]
+pub use stats::{QueueStats, stats};
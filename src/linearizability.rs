@@ -0,0 +1,385 @@
+//! Linearizability checker for histories of concurrent queue operations
+//!
+//! Threads record their operations on a queue with a `Recorder`, which stamps the
+//! invocation and the response of every operation with a shared logical clock. The
+//! history is linearizable if the operations can be put in an order that respects the
+//! clock (an operation that returned before another one was invoked comes first) and
+//! that a sequential FIFO queue could have produced.
+//!
+//! The search is Wing & Gong's, with Lowe's memoization of the pairs of linearized
+//! operations and queue contents that have been tried already. When a history isn't
+//! linearizable, operations are dropped from it, a value at a time, as long as what's
+//! left still isn't, so the history reported is a minimal one.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lcrq::{LCRQ, DequeueResult};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Enqueue(u64),
+    Dequeue(Option<u64>), // `None` if the queue was empty
+}
+
+/// A completed operation
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub thread: usize,
+    pub invoked: u64,   // clock before the operation started
+    pub responded: u64, // clock after it returned
+    pub operation: Operation,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>6}, {:>6}] thread {}: ", self.invoked, self.responded, self.thread)?;
+        match self.operation {
+            Operation::Enqueue(value)       => write!(f, "enqueue({})", value),
+            Operation::Dequeue(Some(value)) => write!(f, "dequeue() -> {}", value),
+            Operation::Dequeue(None)        => write!(f, "dequeue() -> empty"),
+        }
+    }
+}
+
+/// Events recorded by all threads
+#[derive(Default)]
+pub struct History {
+    clock: AtomicU64,
+    events: Mutex<Vec<Event>>,
+}
+
+/// Records the operations of one thread, and adds them to the history when dropped
+pub struct Recorder {
+    history: Arc<History>,
+    thread: usize,
+    events: Vec<Event>,
+}
+
+impl History {
+    pub fn new() -> Arc<History> {
+        Arc::new(History::default())
+    }
+
+    pub fn recorder(self: &Arc<History>, thread: usize) -> Recorder {
+        Recorder { history: self.clone(), thread, events: Vec::new() }
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Panic with a minimal history that isn't linearizable, if the recorded one isn't
+    pub fn assert_linearizable(&self) {
+        if let Err(events) = check(&self.events()) {
+            let lines = events.iter().map(|event| event.to_string()).collect::<Vec<_>>();
+            panic!("history isn't linearizable, a minimal part of it:\n{}", lines.join("\n"));
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+impl Recorder {
+    /// Enqueue `value` and record it. Enqueues into a closed queue aren't recorded.
+    pub fn enqueue<const N: usize>(&mut self, queue: &LCRQ<u64, N>, value: u64) -> bool {
+        let invoked = self.history.tick();
+        let enqueued = queue.enqueue(value).is_ok();
+        if enqueued {
+            self.record(invoked, Operation::Enqueue(value));
+        }
+        enqueued
+    }
+
+    /// Dequeue a value and record the result. Dequeues from a closed queue aren't recorded.
+    pub fn dequeue<const N: usize>(&mut self, queue: &LCRQ<u64, N>) -> DequeueResult<u64> {
+        let invoked = self.history.tick();
+        let result = queue.dequeue();
+        match result {
+            DequeueResult::Value(value) => self.record(invoked, Operation::Dequeue(Some(value))),
+            DequeueResult::Empty        => self.record(invoked, Operation::Dequeue(None)),
+            DequeueResult::Closed       => {}
+        }
+        result
+    }
+
+    /// Dequeue a value, spinning while the queue is empty. Only the first empty result is
+    /// recorded, which keeps the history short; leaving out dequeues that found the queue
+    /// empty never makes a linearizable history unlinearizable.
+    pub fn dequeue_spinning<const N: usize>(&mut self, queue: &LCRQ<u64, N>) -> u64 {
+        if let DequeueResult::Value(value) = self.dequeue(queue) {
+            return value;
+        }
+        loop {
+            let invoked = self.history.tick();
+            if let DequeueResult::Value(value) = queue.dequeue() {
+                self.record(invoked, Operation::Dequeue(Some(value)));
+                return value;
+            }
+        }
+    }
+
+    fn record(&mut self, invoked: u64, operation: Operation) {
+        let responded = self.history.tick();
+        self.events.push(Event { thread: self.thread, invoked, responded, operation });
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.history.events.lock().unwrap().append(&mut self.events);
+    }
+}
+
+/// Check whether `events` is linearizable. If it isn't, the error holds a minimal part of
+/// it that isn't either, sorted by invocation.
+pub fn check(events: &[Event]) -> Result<(), Vec<Event>> {
+    if is_linearizable(events) {
+        return Ok(());
+    }
+
+    // the events that have to go together: the ones involving a value, and every empty dequeue on its own
+    let mut groups: Vec<Vec<Event>> = Vec::new();
+    for event in events {
+        let value = match event.operation {
+            Operation::Enqueue(value) | Operation::Dequeue(Some(value)) => Some(value),
+            Operation::Dequeue(None) => None,
+        };
+        let group = value.and_then(|value| groups.iter().position(|group| group.iter().any(|other| match other.operation {
+            Operation::Enqueue(other) | Operation::Dequeue(Some(other)) => other == value,
+            Operation::Dequeue(None) => false,
+        })));
+        match group {
+            Some(group) => groups[group].push(event.clone()),
+            None => groups.push(vec![event.clone()]),
+        }
+    }
+
+    let mut shrunk = true;
+    while shrunk {
+        shrunk = false;
+        let mut i = 0;
+        while i < groups.len() {
+            let group = groups.remove(i);
+            if is_linearizable(&groups.concat()) {
+                groups.insert(i, group);
+                i += 1;
+            } else {
+                shrunk = true;
+            }
+        }
+    }
+
+    let mut minimal = groups.concat();
+    minimal.sort_by_key(|event| event.invoked);
+    Err(minimal)
+}
+
+/// Apply `operation` to `queue`, if a sequential queue could have returned its result
+fn apply(queue: &mut VecDeque<u64>, operation: Operation) -> bool {
+    match operation {
+        Operation::Enqueue(value) => {
+            queue.push_back(value);
+            true
+        }
+        Operation::Dequeue(Some(value)) if queue.front() == Some(&value) => {
+            queue.pop_front();
+            true
+        }
+        Operation::Dequeue(Some(_)) => false,
+        Operation::Dequeue(None) => queue.is_empty(),
+    }
+}
+
+fn undo(queue: &mut VecDeque<u64>, operation: Operation) {
+    match operation {
+        Operation::Enqueue(_)           => { queue.pop_back(); }
+        Operation::Dequeue(Some(value)) => queue.push_front(value),
+        Operation::Dequeue(None)        => {}
+    }
+}
+
+fn is_linearizable(events: &[Event]) -> bool {
+    // entries 2i and 2i+1 are the invocation and response of events[i], and they're
+    // kept in a circular doubly linked list in clock order, with the head at the end
+    let head = 2 * events.len();
+    let mut entries = (0..head).collect::<Vec<_>>();
+    let time = |entry: usize| if entry.is_multiple_of(2) { events[entry / 2].invoked } else { events[entry / 2].responded };
+    entries.sort_by_key(|&entry| time(entry));
+    let mut next = vec![head; head + 1];
+    let mut prev = vec![head; head + 1];
+    let mut last = head;
+    for &entry in &entries {
+        next[last] = entry;
+        prev[entry] = last;
+        last = entry;
+    }
+    next[last] = head;
+    prev[head] = last;
+
+    let mut linearized = vec![0u64; events.len().div_ceil(64)];
+    let mut queue = VecDeque::new();
+    let mut tried = HashSet::new();
+    let mut stack: Vec<usize> = Vec::new(); // the linearized events, in order
+    let mut entry = next[head];
+    while entry != head {
+        let event = entry / 2;
+        if entry.is_multiple_of(2) {
+            if apply(&mut queue, events[event].operation) {
+                linearized[event / 64] |= 1 << (event % 64);
+                if tried.insert((linearized.clone(), queue.clone())) {
+                    // take the event out of the list, and start over with the rest
+                    for entry in [2 * event, 2 * event + 1] {
+                        next[prev[entry]] = next[entry];
+                        prev[next[entry]] = prev[entry];
+                    }
+                    stack.push(event);
+                    entry = next[head];
+                    continue;
+                }
+                linearized[event / 64] &= !(1 << (event % 64));
+                undo(&mut queue, events[event].operation);
+            }
+            entry = next[entry];
+        } else {
+            // an event returned before it could be linearized, so an earlier choice was wrong
+            let event = match stack.pop() {
+                Some(event) => event,
+                None => return false,
+            };
+            linearized[event / 64] &= !(1 << (event % 64));
+            undo(&mut queue, events[event].operation);
+            for entry in [2 * event + 1, 2 * event] {
+                next[prev[entry]] = entry;
+                prev[next[entry]] = entry;
+            }
+            entry = next[2 * event];
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::spawn;
+    use super::*;
+    use super::Operation::{Enqueue, Dequeue};
+
+    fn event(thread: usize, invoked: u64, responded: u64, operation: Operation) -> Event {
+        Event { thread, invoked, responded, operation }
+    }
+
+    #[test]
+    fn test_sequential() {
+        let events = [
+            event(0, 0, 1, Enqueue(1)),
+            event(0, 2, 3, Enqueue(2)),
+            event(1, 4, 5, Dequeue(Some(1))),
+            event(1, 6, 7, Dequeue(Some(2))),
+            event(1, 8, 9, Dequeue(None)),
+        ];
+        assert_eq!(check(&events), Ok(()));
+    }
+
+    #[test]
+    fn test_overlapping_enqueues_in_either_order() {
+        for &(first, second) in &[(1, 2), (2, 1)] {
+            let events = [
+                event(0, 0, 3, Enqueue(1)),
+                event(1, 1, 2, Enqueue(2)),
+                event(2, 4, 5, Dequeue(Some(first))),
+                event(2, 6, 7, Dequeue(Some(second))),
+            ];
+            assert_eq!(check(&events), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_dequeue_overlapping_enqueue() {
+        // the dequeue may take effect before or after the enqueue
+        for &result in &[None, Some(1)] {
+            let mut events = vec![event(0, 0, 3, Enqueue(1)), event(1, 1, 2, Dequeue(result))];
+            if result.is_none() {
+                events.push(event(1, 4, 5, Dequeue(Some(1))));
+            }
+            assert_eq!(check(&events), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_reordered_values() {
+        let events = [
+            event(0, 0, 1, Enqueue(1)),
+            event(2, 0, 9, Enqueue(7)),
+            event(0, 2, 3, Enqueue(2)),
+            event(1, 4, 5, Dequeue(Some(2))),
+            event(1, 6, 7, Dequeue(Some(1))),
+        ];
+        // the enqueue of 7 has nothing to do with it
+        assert_eq!(check(&events), Err(vec![events[0].clone(), events[2].clone(), events[3].clone(), events[4].clone()]));
+    }
+
+    #[test]
+    fn test_empty_while_not_empty() {
+        let events = [
+            event(0, 0, 1, Enqueue(1)),
+            event(1, 2, 3, Dequeue(None)),
+            event(1, 4, 5, Dequeue(Some(1))),
+        ];
+        assert_eq!(check(&events), Err(events.to_vec()));
+    }
+
+    #[test]
+    fn test_lost_and_duplicated_values() {
+        let lost = [event(0, 0, 1, Enqueue(1)), event(0, 2, 3, Enqueue(2)), event(1, 4, 5, Dequeue(Some(2)))];
+        assert_eq!(check(&lost), Err(lost.to_vec()));
+
+        let duplicated = [event(0, 0, 1, Enqueue(1)), event(1, 2, 3, Dequeue(Some(1))), event(1, 4, 5, Dequeue(Some(1)))];
+        assert_eq!(check(&duplicated), Err(duplicated.to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "history isn't linearizable")]
+    fn test_assert_linearizable_panics() {
+        let history = History::new();
+        {
+            let mut recorder = history.recorder(0);
+            recorder.record(0, Dequeue(Some(1)));
+        }
+        history.assert_linearizable();
+    }
+
+    #[test]
+    fn test_recorded_lcrq() {
+        let lcrq = Arc::new(LCRQ::<u64, 4>::with_ring_size());
+        let history = History::new();
+        let producers = (0..3).map(|p| {
+            let lcrq = lcrq.clone();
+            let mut recorder = history.recorder(p);
+            spawn(move || {
+                for i in 0..100 {
+                    assert!(recorder.enqueue(&lcrq, p as u64 * 100 + i));
+                }
+            })
+        }).collect::<Vec<_>>();
+        let consumers = (0..2).map(|c| {
+            let lcrq = lcrq.clone();
+            let mut recorder = history.recorder(3 + c);
+            spawn(move || {
+                for _ in 0..150 {
+                    recorder.dequeue_spinning(&lcrq);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in producers.into_iter().chain(consumers) {
+            thread.join().unwrap();
+        }
+        let values = history.events().iter().filter(|event| matches!(event.operation, Dequeue(Some(_)))).count();
+        assert_eq!(values, 300);
+        history.assert_linearizable();
+    }
+}
@@ -0,0 +1,121 @@
+//! Metrics of a single queue, rendered in the Prometheus text exposition format
+//!
+//! Unlike `stats()`, which adds up events of all queues, every `LCRQ` counts its own
+//! operations when the `metrics` feature is on.
+
+use std::fmt::Write;
+
+use lcrq::LCRQ;
+
+/// Counters and gauges of one queue, see `LCRQ::metrics`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueMetrics {
+    pub enqueued: u64,           // values enqueued
+    pub dequeued: u64,           // values dequeued
+    pub rings_closed: u64,       // CRQs closed because they were full or starving, or by `close`
+    pub segments_allocated: u64, // CRQs allocated after the first one, including ones that lost the race to be linked
+    pub depth: u64,              // values in the queue, from `len_estimate`
+    pub segments: u64,           // CRQs currently linked
+}
+
+/// Render the metrics of `queue` in the Prometheus text exposition format, with every
+/// metric name prefixed by `name`, which has to be a valid metric name itself
+pub fn render_prometheus<T, const N: usize>(queue: &LCRQ<T, N>, name: &str) -> String {
+    let metrics = queue.metrics();
+    let mut output = String::new();
+    let mut metric = |suffix: &str, kind: &str, help: &str, value: u64| {
+        // writing to a String can't fail
+        let _ = writeln!(output, "# HELP {}_{} {}", name, suffix, help);
+        let _ = writeln!(output, "# TYPE {}_{} {}", name, suffix, kind);
+        let _ = writeln!(output, "{}_{} {}", name, suffix, value);
+    };
+    metric("enqueued_total", "counter", "Values enqueued.", metrics.enqueued);
+    metric("dequeued_total", "counter", "Values dequeued.", metrics.dequeued);
+    metric("rings_closed_total", "counter", "Ring segments closed.", metrics.rings_closed);
+    metric("segments_allocated_total", "counter", "Ring segments allocated.", metrics.segments_allocated);
+    metric("depth", "gauge", "Estimated number of values in the queue.", metrics.depth);
+    metric("segments", "gauge", "Ring segments currently linked.", metrics.segments);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::spawn;
+    use std::sync::Arc;
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        assert_eq!(lcrq.metrics(), QueueMetrics { segments: 1, ..QueueMetrics::default() });
+
+        for i in 0..10 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+        assert_eq!(lcrq.metrics().depth, 10);
+        for _ in 0..3 {
+            assert!(lcrq.dequeue().value().is_some());
+        }
+        let metrics = lcrq.metrics();
+        assert_eq!(metrics.enqueued, 10);
+        assert_eq!(metrics.dequeued, 3);
+        // 4 values fit in every CRQ, so the values take up 3 of them
+        assert_eq!(metrics.segments_allocated, 2);
+        assert_eq!(metrics.rings_closed, 2);
+        assert_eq!(metrics.segments, 3);
+
+        lcrq.close();
+        assert_eq!(lcrq.metrics().rings_closed, 3);
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        let lcrq = LCRQ::<u64>::new();
+        assert!(lcrq.enqueue_batch(&[1, 2, 3]).is_ok());
+        assert_eq!(lcrq.dequeue().value(), Some(1));
+        assert_eq!(render_prometheus(&lcrq, "jobs"), "\
+# HELP jobs_enqueued_total Values enqueued.
+# TYPE jobs_enqueued_total counter
+jobs_enqueued_total 3
+# HELP jobs_dequeued_total Values dequeued.
+# TYPE jobs_dequeued_total counter
+jobs_dequeued_total 1
+# HELP jobs_rings_closed_total Ring segments closed.
+# TYPE jobs_rings_closed_total counter
+jobs_rings_closed_total 0
+# HELP jobs_segments_allocated_total Ring segments allocated.
+# TYPE jobs_segments_allocated_total counter
+jobs_segments_allocated_total 0
+# HELP jobs_depth Estimated number of values in the queue.
+# TYPE jobs_depth gauge
+jobs_depth 2
+# HELP jobs_segments Ring segments currently linked.
+# TYPE jobs_segments gauge
+jobs_segments 1
+");
+    }
+
+    #[test]
+    fn test_counts_multithreaded() {
+        let lcrq = Arc::new(LCRQ::<u64, 16>::with_ring_size());
+        let per_thread = 10_000;
+        let threads = (0..4).map(|_| {
+            let lcrq = lcrq.clone();
+            spawn(move || {
+                for i in 0..per_thread {
+                    assert!(lcrq.enqueue(i).is_ok());
+                    if i % 2 == 0 {
+                        assert!(lcrq.dequeue().value().is_some());
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let metrics = lcrq.metrics();
+        assert_eq!(metrics.enqueued, 4 * per_thread);
+        assert_eq!(metrics.dequeued, 2 * per_thread);
+        assert!(metrics.segments_allocated >= metrics.rings_closed);
+    }
+}
@@ -0,0 +1,557 @@
+//! Model checker for the queues, enabled by the `model` feature
+//!
+//! `Model::check` runs a closure over and over, every time with a different interleaving
+//! of the threads it starts with `model::spawn`. The threads are real threads, but only
+//! one of them runs at a time, and every operation in `atomics` is a point where the
+//! scheduler may switch to another one. Interleavings are explored depth first, so
+//! without a preemption bound every interleaving of the atomic operations is tried once.
+//! A preemption bound limits how often a thread is switched away from while it could
+//! keep running. That keeps the number of executions manageable, and most concurrency
+//! bugs only take a preemption or two to show up.
+//!
+//! The closure checks the outcome of each execution with assertions. When one of them
+//! fails, or any other thread panics, `check` panics with the schedule that led there.
+//! Operations on atomics that don't go through `atomics` aren't scheduling points, and
+//! nothing may block other than `JoinHandle::join`, or the execution never finishes.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+use lcrq;
+
+/// Explores the interleavings of a concurrent closure, see the module documentation
+pub struct Model {
+    preemption_bound: Option<usize>,
+    max_steps: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Runnable,
+    Joining(usize), // waiting for the thread with this id to finish
+    Finished,
+}
+
+/// A point where more than one thread could run next
+struct Choice {
+    options: Vec<usize>, // the threads that could run, the one that was running first
+    chosen: usize,       // index into `options`
+}
+
+struct State {
+    threads: Vec<Status>, // indexed by thread id, the closure passed to `check` is 0
+    active: usize,        // the thread that may run
+    choices: Vec<Choice>, // the choices of the previous execution, replayed up to the last one
+    position: usize,      // index of the next choice in `choices`
+    trace: Vec<usize>,    // the thread that ran after every scheduling point
+    preemptions: usize,
+    preemption_bound: Option<usize>,
+    steps: usize,
+    max_steps: usize,
+    failure: Option<String>, // what went wrong first, if anything
+    aborted: bool,           // set when the execution can't finish, so every thread unwinds
+}
+
+struct Execution {
+    state: Mutex<State>,
+    turn: Condvar, // notified whenever `active` changes or a thread finishes
+}
+
+/// Payload of the panics that unwind the threads of an aborted execution
+struct Aborted;
+
+thread_local! {
+    // the execution the current thread belongs to, and its thread id in it
+    static CURRENT: RefCell<Option<(Arc<Execution>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Default for Model {
+    fn default() -> Model {
+        Model::new()
+    }
+}
+
+impl Model {
+    /// A model that explores every interleaving
+    pub fn new() -> Model {
+        Model { preemption_bound: None, max_steps: 10_000 }
+    }
+
+    /// Only explore interleavings with at most `bound` preemptions
+    pub fn preemption_bound(self, bound: usize) -> Model {
+        Model { preemption_bound: Some(bound), ..self }
+    }
+
+    /// Give up on an execution after `max_steps` scheduling points, assuming some thread
+    /// spins forever waiting for another one
+    pub fn max_steps(self, max_steps: usize) -> Model {
+        Model { max_steps, ..self }
+    }
+
+    /// Run `f` once for every interleaving of the threads it spawns with `model::spawn`,
+    /// and return the number of executions. Panics if any execution panics.
+    pub fn check<F: Fn() + Send + Sync + 'static>(&self, f: F) -> usize {
+        let f = Arc::new(f);
+        let mut choices = Vec::new();
+        let mut executions = 0;
+        loop {
+            executions += 1;
+            let execution = Arc::new(Execution {
+                state: Mutex::new(State {
+                    threads: vec![Status::Runnable], active: 0, choices, position: 0, trace: Vec::new(),
+                    preemptions: 0, preemption_bound: self.preemption_bound, steps: 0, max_steps: self.max_steps,
+                    failure: None, aborted: false,
+                }),
+                turn: Condvar::new(),
+            });
+            let f = f.clone();
+            let _ = start_thread(execution.clone(), 0, move || f());
+
+            let mut state = execution.wait_until_finished();
+            if let Some(ref failure) = state.failure {
+                panic!("execution {} failed: {}\nschedule: {}", executions, failure, format_trace(&state.trace));
+            }
+            choices = mem::take(&mut state.choices);
+            if !next_schedule(&mut choices) {
+                return executions;
+            }
+        }
+    }
+}
+
+/// Move on to the schedule after `choices` in depth first order. Returns false if all of
+/// them have been explored.
+fn next_schedule(choices: &mut Vec<Choice>) -> bool {
+    while let Some(mut choice) = choices.pop() {
+        if choice.chosen + 1 < choice.options.len() {
+            choice.chosen += 1;
+            choices.push(choice);
+            return true;
+        }
+    }
+    false
+}
+
+/// The trace as runs of the same thread, like "0x3 1x2 0x1"
+fn format_trace(trace: &[usize]) -> String {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for &thread in trace {
+        match runs.last_mut() {
+            Some(run) if run.0 == thread => run.1 += 1,
+            _ => runs.push((thread, 1)),
+        }
+    }
+    runs.iter().map(|&(thread, count)| format!("{}x{}", thread, count)).collect::<Vec<_>>().join(" ")
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "panic".to_string()),
+    }
+}
+
+impl State {
+    /// Pick the thread to run after `current` reached a scheduling point, or `None` if no
+    /// thread can run
+    fn schedule(&mut self, current: usize) -> Option<usize> {
+        let current_runnable = self.threads[current] == Status::Runnable;
+        let may_preempt = self.preemption_bound.is_none_or(|bound| self.preemptions < bound);
+
+        let mut options = Vec::new();
+        if current_runnable {
+            options.push(current);
+        }
+        if !current_runnable || may_preempt {
+            options.extend((0..self.threads.len()).filter(|&thread| thread != current && self.threads[thread] == Status::Runnable));
+        }
+
+        let chosen = match options.len() {
+            0 => return None,
+            1 => 0,
+            _ => {
+                if self.position == self.choices.len() {
+                    self.choices.push(Choice { options: options.clone(), chosen: 0 });
+                }
+                let (replayed, chosen) = {
+                    let choice = &self.choices[self.position];
+                    (choice.options == options, choice.chosen)
+                };
+                self.position += 1;
+                if !replayed {
+                    self.fail("the closure doesn't behave the same way every time it's given the same schedule".to_string());
+                }
+                cmp::min(chosen, options.len() - 1)
+            }
+        };
+        let next = options[chosen];
+        if current_runnable && next != current {
+            self.preemptions += 1;
+        }
+        self.trace.push(next);
+        Some(next)
+    }
+
+    fn fail(&mut self, failure: String) {
+        if self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+    }
+
+    fn abort(&mut self, failure: String) {
+        self.fail(failure);
+        self.aborted = true;
+    }
+}
+
+impl Execution {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the lock is never held while user code runs, so it can't be poisoned
+        self.state.lock().unwrap()
+    }
+
+    /// Let the scheduler pick the next thread to run after thread `me` changed its status
+    /// to `status`, and wait until it's `me`'s turn again
+    fn switch(&self, me: usize, status: Status) {
+        let mut state = self.lock();
+        if state.aborted {
+            drop(state);
+            return unwind_aborted();
+        }
+
+        state.threads[me] = status;
+        state.steps += 1;
+        if state.steps > state.max_steps {
+            let failure = format!("the execution didn't finish in {} steps, so a thread probably spins forever", state.max_steps);
+            state.abort(failure);
+        } else if let Some(next) = state.schedule(me) {
+            state.active = next;
+        } else {
+            state.abort("all threads are waiting for each other".to_string());
+        }
+        self.turn.notify_all();
+        self.wait_for_turn(state, me);
+    }
+
+    fn wait_for_turn(&self, mut state: MutexGuard<'_, State>, me: usize) {
+        while state.active != me && !state.aborted {
+            state = self.turn.wait(state).unwrap();
+        }
+        if state.aborted {
+            drop(state);
+            unwind_aborted();
+        }
+    }
+
+    fn finish(&self, me: usize, panic: Option<&(dyn Any + Send)>) {
+        let mut state = self.lock();
+        if let Some(payload) = panic {
+            if !payload.is::<Aborted>() {
+                state.fail(format!("thread {} panicked: {}", me, panic_message(payload)));
+            }
+        }
+
+        state.threads[me] = Status::Finished;
+        for thread in state.threads.iter_mut() {
+            if *thread == Status::Joining(me) {
+                *thread = Status::Runnable;
+            }
+        }
+        if !state.aborted {
+            match state.schedule(me) {
+                Some(next) => state.active = next,
+                None if state.threads.iter().all(|&thread| thread == Status::Finished) => {}
+                None => state.abort("all threads are waiting for each other".to_string()),
+            }
+        }
+        self.turn.notify_all();
+    }
+
+    fn wait_until_finished(&self) -> MutexGuard<'_, State> {
+        let mut state = self.lock();
+        while state.threads.iter().any(|&thread| thread != Status::Finished) {
+            state = self.turn.wait(state).unwrap();
+        }
+        state
+    }
+}
+
+fn unwind_aborted() {
+    // destructors that run while unwinding may reach scheduling points, and just carry on
+    if !thread::panicking() {
+        panic::resume_unwind(Box::new(Aborted));
+    }
+}
+
+fn current() -> Option<(Arc<Execution>, usize)> {
+    CURRENT.try_with(|current| current.borrow().clone()).ok().and_then(|current| current)
+}
+
+/// A scheduling point, called before every operation in `atomics`. Does nothing outside
+/// of `Model::check`.
+pub(crate) fn yield_point() {
+    if let Some((execution, me)) = current() {
+        execution.switch(me, Status::Runnable);
+    }
+}
+
+fn start_thread<T, F>(execution: Arc<Execution>, me: usize, f: F) -> thread::JoinHandle<T>
+        where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+    thread::spawn(move || {
+        CURRENT.with(|current| *current.borrow_mut() = Some((execution.clone(), me)));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            execution.wait_for_turn(execution.lock(), me);
+            f()
+        }));
+        lcrq::release_participants();
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        execution.finish(me, result.as_ref().err().map(|payload| &**payload));
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    })
+}
+
+/// Handle of a thread started with `model::spawn`
+pub struct JoinHandle<T> {
+    id: usize,
+    handle: thread::JoinHandle<T>,
+}
+
+/// Start a thread in the execution the current thread belongs to. Panics if called
+/// outside of `Model::check`.
+pub fn spawn<T, F>(f: F) -> JoinHandle<T> where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+    let (execution, _) = current().expect("model::spawn can only be called inside Model::check");
+    let id = {
+        let mut state = execution.lock();
+        state.threads.push(Status::Runnable);
+        state.threads.len() - 1
+    };
+    JoinHandle { id, handle: start_thread(execution, id, f) }
+}
+
+impl<T> JoinHandle<T> {
+    /// Wait for the thread to finish, letting the others run meanwhile
+    pub fn join(self) -> thread::Result<T> {
+        if let Some((execution, me)) = current() {
+            let finished = execution.lock().threads[self.id] == Status::Finished;
+            if !finished {
+                execution.switch(me, Status::Joining(self.id));
+            }
+        }
+        self.handle.join()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use super::*;
+    use atomics::{fetch_and_add, load, compare_and_swap};
+    use crq::{CRQ, EnqueueError};
+    use lcrq::LCRQ;
+
+    /// Check that every value enqueued came out exactly once, and that the values of every
+    /// producer came out in the order they went in. `producers` holds the values each
+    /// producer enqueued, `dequeued` the values in the order they were dequeued.
+    fn check_fifo(producers: &[Vec<u64>], dequeued: &[u64]) {
+        let enqueued = producers.iter().flatten().cloned().collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        for value in dequeued {
+            assert!(enqueued.contains(value), "{} was dequeued but never enqueued", value);
+            assert!(seen.insert(*value), "{} was dequeued twice", value);
+        }
+        assert_eq!(seen.len(), enqueued.len(), "values were lost: enqueued {:?}, dequeued {:?}", producers, dequeued);
+        for values in producers {
+            let order = dequeued.iter().filter(|value| values.contains(value)).cloned().collect::<Vec<_>>();
+            assert_eq!(&order, values, "values were reordered");
+        }
+    }
+
+    #[test]
+    fn test_finds_lost_update() {
+        // an increment made of a load and a compare-and-swap that doesn't retry
+        let result = panic::catch_unwind(|| {
+            Model::new().check(|| {
+                let x = Arc::new(AtomicU64::new(0));
+                let threads = (0..2).map(|_| {
+                    let x = x.clone();
+                    spawn(move || {
+                        let value = load(&x);
+                        compare_and_swap(&x, value, value + 1);
+                    })
+                }).collect::<Vec<_>>();
+                for thread in threads {
+                    thread.join().unwrap();
+                }
+                assert_eq!(load(&x), 2);
+            })
+        });
+        let message = panic_message(&*result.unwrap_err());
+        assert!(message.contains("failed"), "{}", message);
+    }
+
+    #[test]
+    fn test_explores_every_interleaving() {
+        // two threads with two scheduling points each, after the spawns: the first thread
+        // can run its two operations before, in between or after the other's
+        let executions = Model::new().check(|| {
+            let x = Arc::new(AtomicU64::new(0));
+            let threads = (0..2).map(|_| {
+                let x = x.clone();
+                spawn(move || {
+                    fetch_and_add(&x, 1);
+                    fetch_and_add(&x, 1);
+                })
+            }).collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(load(&x), 4);
+        });
+        assert!(executions >= 6, "{} executions", executions);
+    }
+
+    #[test]
+    fn test_preemption_bound_limits_executions() {
+        let program = || {
+            let x = Arc::new(AtomicU64::new(0));
+            let threads = (0..3).map(|_| {
+                let x = x.clone();
+                spawn(move || {
+                    fetch_and_add(&x, 1);
+                    fetch_and_add(&x, 1);
+                })
+            }).collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        };
+        let bounded = Model::new().preemption_bound(1).check(program);
+        let unbounded = Model::new().check(program);
+        assert!(bounded < unbounded, "{} bounded, {} unbounded executions", bounded, unbounded);
+    }
+
+    #[test]
+    fn test_spinning_thread_is_reported() {
+        let result = panic::catch_unwind(|| {
+            Model::new().preemption_bound(0).max_steps(100).check(|| {
+                let x = Arc::new(AtomicU64::new(0));
+                let x_clone = x.clone();
+                let waiter = spawn(move || while load(&x_clone) == 0 {});
+                let setter = spawn(move || fetch_and_add(&x, 1));
+                waiter.join().unwrap();
+                setter.join().unwrap();
+            })
+        });
+        let message = panic_message(&*result.unwrap_err());
+        assert!(message.contains("didn't finish"), "{}", message);
+    }
+
+    fn crq_producers_and_consumer<const N: usize>(preemption_bound: usize) {
+        Model::new().preemption_bound(preemption_bound).check(|| {
+            let crq = Arc::new(CRQ::<N>::with_ring_size());
+            let producers = [vec![1, 2], vec![3]].iter().map(|values| {
+                let crq = crq.clone();
+                let values = values.clone();
+                spawn(move || values.into_iter().filter(|&value| crq.enqueue(value).is_ok()).collect::<Vec<_>>())
+            }).collect::<Vec<_>>();
+            let consumer = {
+                let crq = crq.clone();
+                spawn(move || (0..2).filter_map(|_| crq.dequeue()).collect::<Vec<_>>())
+            };
+
+            // values the ring turned away when it closed aren't expected to come out
+            let enqueued = producers.into_iter().map(|producer| producer.join().unwrap()).collect::<Vec<_>>();
+            let mut dequeued = consumer.join().unwrap();
+            while let Some(value) = crq.dequeue() {
+                dequeued.push(value);
+            }
+            check_fifo(&enqueued, &dequeued);
+        });
+    }
+
+    #[test]
+    fn test_crq_producers_and_consumer() {
+        crq_producers_and_consumer::<4>(2);
+    }
+
+    #[test]
+    fn test_crq_wrap_around_and_close() {
+        // two slots for four values, so rings wrap around and close
+        crq_producers_and_consumer::<2>(2);
+    }
+
+    #[test]
+    fn test_crq_consumers() {
+        Model::new().preemption_bound(2).check(|| {
+            let crq = Arc::new(CRQ::<2>::with_ring_size());
+            let consumers = (0..2).map(|_| {
+                let crq = crq.clone();
+                spawn(move || (0..2).filter_map(|_| crq.dequeue()).collect::<Vec<_>>())
+            }).collect::<Vec<_>>();
+            let enqueued = [1, 2, 3].iter().cloned().filter(|&value| crq.enqueue(value).is_ok()).collect::<Vec<_>>();
+
+            let mut dequeued = Vec::new();
+            for consumer in consumers {
+                let values = consumer.join().unwrap();
+                // the values were enqueued in increasing order, so every consumer sees them that way
+                assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "values were reordered: {:?}", values);
+                dequeued.extend(values);
+            }
+            while let Some(value) = crq.dequeue() {
+                dequeued.push(value);
+            }
+            let mut sorted = dequeued.clone();
+            sorted.sort();
+            assert_eq!(sorted, enqueued);
+        });
+    }
+
+    #[test]
+    fn test_crq_close_during_fix_state() {
+        Model::new().check(|| {
+            let crq = Arc::new(CRQ::<2>::with_ring_size());
+            let dequeuer = {
+                let crq = crq.clone();
+                spawn(move || crq.dequeue())
+            };
+            crq.close();
+            assert_eq!(dequeuer.join().unwrap(), None);
+            assert_eq!(crq.enqueue(1), Err(EnqueueError::Closed));
+        });
+    }
+
+    #[test]
+    fn test_lcrq_segment_hops() {
+        Model::new().preemption_bound(1).check(|| {
+            let lcrq = Arc::new(LCRQ::<u64, 2>::with_ring_size());
+            let producers = [vec![1, 2, 3], vec![4, 5]].iter().map(|values| {
+                let lcrq = lcrq.clone();
+                let values = values.clone();
+                spawn(move || {
+                    for &value in &values {
+                        assert!(lcrq.enqueue(value).is_ok());
+                    }
+                    values
+                })
+            }).collect::<Vec<_>>();
+            let consumer = {
+                let lcrq = lcrq.clone();
+                spawn(move || (0..3).filter_map(|_| lcrq.dequeue().value()).collect::<Vec<_>>())
+            };
+
+            let enqueued = producers.into_iter().map(|producer| producer.join().unwrap()).collect::<Vec<_>>();
+            let mut dequeued = consumer.join().unwrap();
+            while let Some(value) = lcrq.dequeue().value() {
+                dequeued.push(value);
+            }
+            check_fifo(&enqueued, &dequeued);
+        });
+    }
+}
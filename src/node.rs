@@ -1,19 +1,22 @@
 use flag_and_u63::FlagAndU63;
-use std::u64;
+use std::sync::atomic::AtomicU64;
+
+use atomics::load;
 
 // TODO: abstract away
 pub const NODE_VALUE_EMPTY: u64 = u64::MAX;
 
-#[repr(align(16))]
+// `repr(C)` keeps the field order, since `compare_and_swap_2` treats a node as two u64s
+#[repr(C, align(16))]
 pub struct Node {
     index_and_safe: FlagAndU63, // highest bit: safe, remaining 63 bits: value
-    value: u64,
+    value: AtomicU64,
     // TODO: pad to cache line size... Assume L2 cache?
 }
 
 impl Node {
     pub fn new(index: u64, value: u64, safe: bool) -> Node {
-        Node { index_and_safe: FlagAndU63::new(safe, index), value: value }
+        Node { index_and_safe: FlagAndU63::new(safe, index), value: AtomicU64::new(value) }
     }
 
     pub fn is_safe(&self) -> bool {
@@ -29,7 +32,7 @@ impl Node {
     }
 
     pub fn value(&self) -> u64 {
-        self.value
+        load(&self.value)
     }
 
     pub fn set_safe(&mut self) {
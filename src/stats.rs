@@ -0,0 +1,184 @@
+//! Operation statistics, collected when the `stats` feature is on.
+//!
+//! Every thread counts events in its own counters, which only it writes to, so counting
+//! needs no atomic read-modify-write. `stats()` adds up the counters of all threads,
+//! including the ones that have exited. Without the feature, `record` does nothing and
+//! compiles away.
+
+/// Something worth counting
+#[derive(Clone, Copy)]
+pub enum Event {
+    EnqueueRetry,       // an enqueuer lost its slot in a CRQ and claimed another one
+    RingClosedFull,     // an enqueuer closed a CRQ because it was full
+    RingClosedStarving, // an enqueuer closed a CRQ because it failed to claim a slot too often
+    SegmentAllocated,   // an LCRQ allocated a new CRQ
+    FixState,           // a dequeuer found a CRQ empty and called `fix_state`
+    FailedNodeCas,      // a compare-and-swap on a ring node failed
+}
+
+#[cfg(not(feature = "stats"))]
+#[inline(always)]
+pub fn record(_event: Event) {}
+
+#[cfg(feature = "stats")]
+pub use self::counters::{record, stats, QueueStats};
+
+#[cfg(feature = "stats")]
+mod counters {
+    use std::ops::Sub;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::Event;
+
+    const EVENTS: usize = 6;
+
+    /// Number of times each `Event` happened, in all threads and all queues
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct QueueStats {
+        pub enqueue_retries: u64,
+        pub rings_closed_full: u64,
+        pub rings_closed_starving: u64,
+        pub segments_allocated: u64,
+        pub fix_state_calls: u64,
+        pub failed_node_cas: u64,
+    }
+
+    impl QueueStats {
+        fn from_counts(counts: [u64; EVENTS]) -> QueueStats {
+            QueueStats {
+                enqueue_retries:       counts[Event::EnqueueRetry as usize],
+                rings_closed_full:     counts[Event::RingClosedFull as usize],
+                rings_closed_starving: counts[Event::RingClosedStarving as usize],
+                segments_allocated:    counts[Event::SegmentAllocated as usize],
+                fix_state_calls:       counts[Event::FixState as usize],
+                failed_node_cas:       counts[Event::FailedNodeCas as usize],
+            }
+        }
+    }
+
+    /// The events between two snapshots
+    impl Sub for QueueStats {
+        type Output = QueueStats;
+
+        fn sub(self, earlier: QueueStats) -> QueueStats {
+            QueueStats {
+                enqueue_retries:       self.enqueue_retries - earlier.enqueue_retries,
+                rings_closed_full:     self.rings_closed_full - earlier.rings_closed_full,
+                rings_closed_starving: self.rings_closed_starving - earlier.rings_closed_starving,
+                segments_allocated:    self.segments_allocated - earlier.segments_allocated,
+                fix_state_calls:       self.fix_state_calls - earlier.fix_state_calls,
+                failed_node_cas:       self.failed_node_cas - earlier.failed_node_cas,
+            }
+        }
+    }
+
+    struct Counters {
+        counts: [AtomicU64; EVENTS],
+    }
+
+    impl Counters {
+        fn new() -> Counters {
+            Counters { counts: [const { AtomicU64::new(0) }; EVENTS] }
+        }
+
+        fn add_to(&self, totals: &mut [u64; EVENTS]) {
+            for (total, count) in totals.iter_mut().zip(self.counts.iter()) {
+                *total += count.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    struct Registry {
+        threads: Vec<Arc<Counters>>, // counters of live threads
+        exited: [u64; EVENTS],       // totals of threads that have exited
+    }
+
+    static REGISTRY: Mutex<Registry> = Mutex::new(Registry { threads: Vec::new(), exited: [0; EVENTS] });
+
+    /// A thread's own counters, which it hands over to the registry when it exits
+    struct ThreadCounters(Arc<Counters>);
+
+    impl ThreadCounters {
+        fn register() -> ThreadCounters {
+            let counters = Arc::new(Counters::new());
+            REGISTRY.lock().unwrap().threads.push(counters.clone());
+            ThreadCounters(counters)
+        }
+    }
+
+    impl Drop for ThreadCounters {
+        fn drop(&mut self) {
+            let mut registry = REGISTRY.lock().unwrap();
+            let registry = &mut *registry;
+            registry.threads.retain(|counters| !Arc::ptr_eq(counters, &self.0));
+            self.0.add_to(&mut registry.exited);
+        }
+    }
+
+    thread_local! {
+        static COUNTERS: ThreadCounters = ThreadCounters::register();
+    }
+
+    pub fn record(event: Event) {
+        // counting after the thread's counters are gone isn't worth a panic
+        let _ = COUNTERS.try_with(|counters| {
+            // only this thread writes its counters, so a plain load and store is enough
+            let count = &counters.0.counts[event as usize];
+            count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        });
+    }
+
+    /// Snapshot of the statistics of all queues. Counters of threads that are busy are
+    /// read one after the other, so the snapshot may miss their latest events.
+    pub fn stats() -> QueueStats {
+        let registry = REGISTRY.lock().unwrap();
+        let mut totals = registry.exited;
+        for counters in &registry.threads {
+            counters.add_to(&mut totals);
+        }
+        QueueStats::from_counts(totals)
+    }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod test {
+    use std::thread::spawn;
+    use super::*;
+    use crq::CRQ;
+    use lcrq::LCRQ;
+
+    // other tests run at the same time and count too, so only lower bounds can be checked
+
+    #[test]
+    fn test_segments_and_full_rings() {
+        let before = stats();
+        let lcrq = LCRQ::<u64, 4>::with_ring_size();
+        for i in 0..10 {
+            assert!(lcrq.enqueue(i).is_ok());
+        }
+        let delta = stats() - before;
+        assert!(delta.segments_allocated >= 2);
+        assert!(delta.rings_closed_full >= 2);
+    }
+
+    #[test]
+    fn test_fix_state() {
+        let before = stats();
+        let crq = CRQ::<4>::with_ring_size();
+        assert_eq!(crq.dequeue(), None);
+        assert!((stats() - before).fix_state_calls >= 1);
+    }
+
+    #[test]
+    fn test_exited_threads_are_counted() {
+        let before = stats();
+        spawn(|| {
+            let crq = CRQ::<4>::with_ring_size();
+            for _ in 0..10 {
+                assert_eq!(crq.dequeue(), None);
+            }
+        }).join().unwrap();
+        assert!((stats() - before).fix_state_calls >= 10);
+    }
+}
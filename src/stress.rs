@@ -0,0 +1,120 @@
+//! Stress tests for per-producer FIFO order and exactly-once delivery
+//!
+//! Every value carries the id of the producer that enqueued it and its sequence number
+//! for that producer. Since every producer enqueues its values in order, every consumer
+//! has to see the values of each producer with increasing sequence numbers, and all
+//! consumers together have to see every value exactly once.
+
+use std::sync::Arc;
+use std::thread::spawn;
+
+use lcrq::LCRQ;
+
+/// The value a producer enqueues as its `sequence`th
+pub fn tag(producer: usize, sequence: u64) -> u64 {
+    (producer as u64) << 32 | sequence
+}
+
+/// The producer and sequence number of a tagged value
+pub fn untag(value: u64) -> (usize, u64) {
+    ((value >> 32) as usize, value & 0xffff_ffff)
+}
+
+/// Run `producers` threads enqueueing `per_producer` tagged values each into an `LCRQ`
+/// with `N` slots per segment, and `consumers` threads dequeuing them until the queue is
+/// closed and drained. Panics if any value is lost, duplicated or out of order.
+pub fn run<const N: usize>(producers: usize, consumers: usize, per_producer: u64) {
+    run_with(producers, consumers, per_producer, LCRQ::<u64, N>::with_ring_size, |queue| {
+        let mut received = Vec::new();
+        while let Some(value) = queue.dequeue_blocking() {
+            received.push(value);
+        }
+        received
+    });
+}
+
+/// Like `run`, with the queue created by `new_queue`, and every consumer thread calling
+/// `consume`, which has to return the values it dequeued in order, once the queue is
+/// closed and drained
+pub fn run_with<const N: usize, C>(producers: usize, consumers: usize, per_producer: u64, new_queue: fn() -> LCRQ<u64, N>, consume: C)
+        where C: Fn(&LCRQ<u64, N>) -> Vec<u64> + Send + Sync + 'static {
+    let queue = Arc::new(new_queue());
+    let consume = Arc::new(consume);
+    let producer_threads = (0..producers).map(|producer| {
+        let queue = queue.clone();
+        spawn(move || {
+            for sequence in 0..per_producer {
+                assert!(queue.enqueue(tag(producer, sequence)).is_ok());
+            }
+        })
+    }).collect::<Vec<_>>();
+    let consumer_threads = (0..consumers).map(|_| {
+        let queue = queue.clone();
+        let consume = consume.clone();
+        spawn(move || consume(&queue))
+    }).collect::<Vec<_>>();
+
+    for producer in producer_threads {
+        producer.join().unwrap();
+    }
+    queue.close();
+    let received = consumer_threads.into_iter().map(|consumer| consumer.join().unwrap()).collect::<Vec<_>>();
+    verify(producers, per_producer, &received);
+}
+
+/// Check the values every consumer received, in the order it received them
+pub fn verify(producers: usize, per_producer: u64, received: &[Vec<u64>]) {
+    let mut delivered = vec![vec![false; per_producer as usize]; producers];
+    for (consumer, values) in received.iter().enumerate() {
+        let mut last = vec![None; producers];
+        for &value in values {
+            let (producer, sequence) = untag(value);
+            assert!(producer < producers && sequence < per_producer, "consumer {} received {:#x}, which no producer sent", consumer, value);
+            if let Some(previous) = last[producer] {
+                assert!(sequence > previous, "consumer {} received value {} of producer {} after value {}", consumer, sequence, producer, previous);
+            }
+            last[producer] = Some(sequence);
+            assert!(!delivered[producer][sequence as usize], "value {} of producer {} was delivered twice", sequence, producer);
+            delivered[producer][sequence as usize] = true;
+        }
+    }
+    for (producer, delivered) in delivered.iter().enumerate() {
+        if let Some(sequence) = delivered.iter().position(|&delivered| !delivered) {
+            panic!("value {} of producer {} was never delivered", sequence, producer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tag() {
+        assert_eq!(untag(tag(0, 0)), (0, 0));
+        assert_eq!(untag(tag(3, 12345)), (3, 12345));
+    }
+
+    #[test]
+    fn test_verify_accepts_interleaved_consumers() {
+        verify(2, 3, &[vec![tag(0, 0), tag(1, 0), tag(0, 2)], vec![tag(1, 1), tag(0, 1), tag(1, 2)]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "received value 0 of producer 1 after value 1")]
+    fn test_verify_reordered() {
+        verify(2, 2, &[vec![tag(0, 0), tag(1, 1), tag(0, 1), tag(1, 0)]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "value 1 of producer 0 was delivered twice")]
+    fn test_verify_duplicated() {
+        verify(1, 2, &[vec![tag(0, 0), tag(0, 1)], vec![tag(0, 1)]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "value 1 of producer 0 was never delivered")]
+    fn test_verify_lost() {
+        verify(1, 3, &[vec![tag(0, 0), tag(0, 2)]]);
+    }
+}
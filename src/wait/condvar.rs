@@ -0,0 +1,44 @@
+//! Sleeping on a condition variable. Every notification bumps `sequence` under the lock,
+//! and sleepers only go to sleep while it still holds the value they read before their
+//! last poll.
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+pub struct Sleepers {
+    sequence: Mutex<u64>,
+    condvar: Condvar,
+}
+
+impl Sleepers {
+    pub fn new() -> Sleepers {
+        Sleepers { sequence: Mutex::new(0), condvar: Condvar::new() }
+    }
+
+    pub fn current_sequence(&self) -> u64 {
+        *self.sequence.lock().unwrap()
+    }
+
+    /// Sleep until notified, unless a notification came after `seen` was read. May return
+    /// early.
+    pub fn sleep(&self, seen: u64, timeout: Option<Duration>) {
+        let sequence = self.sequence.lock().unwrap();
+        if *sequence != seen {
+            return;
+        }
+        match timeout {
+            None => drop(self.condvar.wait(sequence).unwrap()),
+            Some(timeout) => drop(self.condvar.wait_timeout(sequence, timeout).unwrap()),
+        }
+    }
+
+    /// Wake up at most `count` sleepers
+    pub fn notify(&self, count: usize) {
+        *self.sequence.lock().unwrap() += 1;
+        if count == 1 {
+            self.condvar.notify_one();
+        } else {
+            self.condvar.notify_all();
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Sleeping on a Linux futex. Sleepers wait on the address of `sequence`, and every
+//! notification bumps it before waking, so a sleeper that read the old value can't miss
+//! the wakeup: the kernel refuses to put it to sleep.
+
+use std::cmp;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use libc;
+
+pub struct Sleepers {
+    sequence: AtomicU32, // the futex word
+}
+
+impl Sleepers {
+    pub fn new() -> Sleepers {
+        Sleepers { sequence: AtomicU32::new(0) }
+    }
+
+    pub fn current_sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst) as u64
+    }
+
+    /// Sleep until notified, unless a notification came after `seen` was read. May return
+    /// early, when interrupted.
+    pub fn sleep(&self, seen: u64, timeout: Option<Duration>) {
+        futex_wait(&self.sequence, seen as u32, timeout);
+    }
+
+    /// Wake up at most `count` sleepers
+    pub fn notify(&self, count: usize) {
+        self.sequence.fetch_add(1, Ordering::SeqCst);
+        futex_wake(&self.sequence, cmp::min(count, i32::MAX as usize) as i32);
+    }
+}
+
+/// Sleep until woken, as long as `word` still holds `expected`
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timespec_ptr = match timespec {
+        Some(ref timespec) => timespec as *const libc::timespec,
+        None => ptr::null(),
+    };
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                      expected, timespec_ptr);
+    }
+}
+
+/// Wake up at most `count` threads sleeping on `word`
+fn futex_wake(word: &AtomicU32, count: i32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG, count);
+    }
+}
@@ -0,0 +1,283 @@
+//! Parking for consumers that wait for a queue to become non-empty, and wakers for
+//! futures that do the same.
+//!
+//! Waiters register themselves before they check the queue one last time and go to sleep,
+//! and notifiers only do more than a single atomic load when someone is registered.
+//! Polls run without holding any lock, so a poll may notify the queue it's waiting on.
+//!
+//! On Linux, the `futex` feature makes waiters sleep on a futex keyed on a sequence word,
+//! which the queue keeps next to its tail. Everywhere else, and without the feature,
+//! waiters sleep on a condition variable.
+
+use std::hint;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+#[cfg(all(target_os = "linux", feature = "futex"))]
+mod futex;
+#[cfg(not(all(target_os = "linux", feature = "futex")))]
+mod condvar;
+
+mod wakers;
+
+#[cfg(all(target_os = "linux", feature = "futex"))]
+use self::futex::Sleepers;
+#[cfg(not(all(target_os = "linux", feature = "futex")))]
+use self::condvar::Sleepers;
+pub use self::wakers::WakerList;
+
+/// Number of times to poll before going to sleep
+const SPIN_LIMIT: usize = 100;
+
+/// Poll `ready` a bounded number of times, in the hope that sleeping won't be necessary
+fn spin<R, F: FnMut() -> Option<R>>(ready: &mut F) -> Option<R> {
+    for _ in 0..SPIN_LIMIT {
+        if let Some(result) = ready() {
+            return Some(result);
+        }
+        hint::spin_loop();
+    }
+    None
+}
+
+/// Threads waiting for something, like a queue to become non-empty
+pub struct WaitQueue {
+    waiters: AtomicUsize, // threads that are asleep, or about to check one last time before sleeping
+    sleepers: Sleepers,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue { waiters: AtomicUsize::new(0), sleepers: Sleepers::new() }
+    }
+
+    /// Wake up one waiting thread, if there is one
+    pub fn notify_one(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.sleepers.notify(1);
+        }
+    }
+
+    /// Wake up all waiting threads
+    pub fn notify_all(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.sleepers.notify(usize::MAX);
+        }
+    }
+
+    /// Poll `ready` until it returns something, sleeping between polls once spinning for a
+    /// while didn't help. Gives up and returns `None` when `deadline` passes.
+    ///
+    /// Whatever makes `ready` succeed must be followed by a call to `notify_one` or
+    /// `notify_all`, or a sleeping waiter might not notice it.
+    pub fn wait_until<R, F: FnMut() -> Option<R>>(&self, deadline: Option<Instant>, mut ready: F) -> Option<R> {
+        if let Some(result) = spin(&mut ready) {
+            return Some(result);
+        }
+
+        // registering before the last poll means a notifier either sees us waiting, or we
+        // see what it did
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let result = loop {
+            // read before polling, so a notification after the poll changes it
+            let seen = self.sleepers.current_sequence();
+            if let Some(result) = ready() {
+                break Some(result);
+            }
+            let timeout = match deadline {
+                None => None,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    Some(deadline - now)
+                }
+            };
+            // woken, timed out, interrupted or already notified: poll again either way
+            self.sleepers.sleep(seen, timeout);
+        };
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::{self, spawn};
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+    use super::{WaitQueue, SPIN_LIMIT};
+
+    fn take_token(tokens: &AtomicUsize) -> Option<()> {
+        let mut available = tokens.load(Ordering::SeqCst);
+        while available > 0 {
+            match tokens.compare_exchange(available, available - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(()),
+                Err(current) => available = current,
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_notification_between_last_poll_and_sleep() {
+        // the producer hands out its token and notifies right after the waiter's last poll,
+        // before the waiter gets to sleep
+        let queue = Arc::new(WaitQueue::new());
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let (polled_sender, polled) = channel();
+        let producer = {
+            let queue = queue.clone();
+            let tokens = tokens.clone();
+            spawn(move || {
+                polled.recv().unwrap();
+                tokens.fetch_add(1, Ordering::SeqCst);
+                queue.notify_one();
+            })
+        };
+
+        let (done_sender, done) = channel();
+        let consumer = spawn(move || {
+            let mut polls = 0;
+            queue.wait_until(None, || {
+                polls += 1;
+                if polls == SPIN_LIMIT + 1 {
+                    polled_sender.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(20));
+                    return None;
+                }
+                take_token(&tokens)
+            }).unwrap();
+            done_sender.send(()).unwrap();
+        });
+
+        done.recv_timeout(Duration::from_secs(10)).expect("The consumer missed its wakeup");
+        producer.join().unwrap();
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn test_no_lost_wakeups() {
+        // every round hands out one token per producer, each followed by a single
+        // `notify_one`, and waits until all of them are taken. Consumers go to sleep
+        // between rounds, so a lost wakeup leaves a token behind that nobody takes.
+        let queue = Arc::new(WaitQueue::new());
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let taken = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let producer_count = 4;
+        let rounds = 1_000;
+
+        let consumers = (0..4).map(|_| {
+            let queue = queue.clone();
+            let tokens = tokens.clone();
+            let taken = taken.clone();
+            let stop = stop.clone();
+            spawn(move || {
+                let ready = || if stop.load(Ordering::SeqCst) { Some(false) } else { take_token(&tokens).map(|_| true) };
+                while queue.wait_until(None, ready).unwrap() {
+                    taken.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let round_start = Arc::new(Barrier::new(producer_count + 1));
+        let producers = (0..producer_count).map(|_| {
+            let queue = queue.clone();
+            let tokens = tokens.clone();
+            let round_start = round_start.clone();
+            spawn(move || {
+                for _ in 0..rounds {
+                    round_start.wait();
+                    tokens.fetch_add(1, Ordering::SeqCst);
+                    queue.notify_one();
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for round in 0..rounds {
+            round_start.wait();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while taken.load(Ordering::SeqCst) < (round + 1) * producer_count {
+                assert!(Instant::now() < deadline, "A consumer missed its wakeup in round {}", round);
+                thread::yield_now();
+            }
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        queue.notify_all();
+        for thread in producers.into_iter().chain(consumers) {
+            thread.join().unwrap();
+        }
+        assert_eq!(tokens.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_spurious_wakeups_are_not_returned() {
+        let queue = Arc::new(WaitQueue::new());
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        let consumers = (0..8).map(|_| {
+            let queue = queue.clone();
+            let tokens = tokens.clone();
+            let finished = finished.clone();
+            spawn(move || {
+                queue.wait_until(None, || take_token(&tokens)).unwrap();
+                finished.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect::<Vec<_>>();
+
+        // wake everybody up over and over without handing out anything
+        let stop = Arc::new(AtomicBool::new(false));
+        let notifiers = (0..4).map(|_| {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    queue.notify_all();
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+        stop.store(true, Ordering::SeqCst);
+        for notifier in notifiers {
+            notifier.join().unwrap();
+        }
+
+        tokens.fetch_add(consumers.len(), Ordering::SeqCst);
+        queue.notify_all();
+        for consumer in consumers {
+            consumer.join().unwrap();
+        }
+        assert_eq!(finished.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn test_deadline_despite_notifications() {
+        let queue = Arc::new(WaitQueue::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let notifier = {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    queue.notify_one();
+                }
+            })
+        };
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(50);
+        assert_eq!(queue.wait_until(Some(start + timeout), || None::<()>), None);
+        assert!(start.elapsed() >= timeout);
+
+        stop.store(true, Ordering::SeqCst);
+        notifier.join().unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Wakers of futures that wait for a queue to become non-empty. Like the blocking
+//! waiters, futures register before they check the queue one last time, and notifiers
+//! only take the lock when someone is registered.
+
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Waker;
+
+pub struct WakerList {
+    registered: AtomicUsize, // number of entries in `wakers`
+    wakers: Mutex<WakerEntries>,
+}
+
+struct WakerEntries {
+    next_key: u64,
+    entries: Vec<(u64, Waker)>, // oldest registration first
+}
+
+impl WakerList {
+    pub fn new() -> WakerList {
+        WakerList { registered: AtomicUsize::new(0), wakers: Mutex::new(WakerEntries { next_key: 0, entries: Vec::new() }) }
+    }
+
+    /// Register `waker`, or replace the waker registered under `key` if it's still
+    /// registered. `key` is set to the key of the registration.
+    pub fn register(&self, key: &mut Option<u64>, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if let Some(key) = *key {
+            if let Some(entry) = wakers.entries.iter_mut().find(|entry| entry.0 == key) {
+                if !entry.1.will_wake(waker) {
+                    entry.1 = waker.clone();
+                }
+                return;
+            }
+        }
+
+        let new_key = wakers.next_key;
+        wakers.next_key += 1;
+        wakers.entries.push((new_key, waker.clone()));
+        self.registered.fetch_add(1, Ordering::SeqCst);
+        *key = Some(new_key);
+    }
+
+    /// Remove the registration under `key`. Returns false if it had already been removed
+    /// by a notification.
+    pub fn deregister(&self, key: u64) -> bool {
+        let mut wakers = self.wakers.lock().unwrap();
+        match wakers.entries.iter().position(|entry| entry.0 == key) {
+            Some(index) => {
+                wakers.entries.remove(index);
+                self.registered.fetch_sub(1, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake the oldest registered waker, if there is one, and remove its registration
+    pub fn wake_one(&self) {
+        if self.registered.load(Ordering::SeqCst) > 0 {
+            let waker = {
+                let mut wakers = self.wakers.lock().unwrap();
+                if wakers.entries.is_empty() {
+                    return;
+                }
+                self.registered.fetch_sub(1, Ordering::SeqCst);
+                wakers.entries.remove(0).1
+            };
+            waker.wake();
+        }
+    }
+
+    /// Wake all registered wakers and remove their registrations
+    pub fn wake_all(&self) {
+        if self.registered.load(Ordering::SeqCst) > 0 {
+            let entries = {
+                let mut wakers = self.wakers.lock().unwrap();
+                self.registered.store(0, Ordering::SeqCst);
+                mem::take(&mut wakers.entries)
+            };
+            for (_, waker) in entries {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Number of registered wakers
+    #[cfg(test)]
+    pub fn registered(&self) -> usize {
+        self.registered.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,137 @@
+//! Checks that dropping queues frees everything they allocated.
+//!
+//! Runs without the default test harness, since the harness allocates on its own
+//! threads and would make the global allocation counts unreliable.
+
+extern crate concurrent_queue;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::spawn;
+
+use concurrent_queue::crq::RING_SIZE;
+use concurrent_queue::lcrq::LCRQ;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREED.fetch_add(1, Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn live_allocations() -> usize {
+    ALLOCATED.load(Ordering::SeqCst) - FREED.load(Ordering::SeqCst)
+}
+
+fn assert_no_leaks<F: FnOnce()>(name: &str, f: F) {
+    let before = live_allocations();
+    f();
+    let after = live_allocations();
+    assert_eq!(before, after, "{} leaked {} allocations", name, after as isize - before as isize);
+    println!("test {} ... ok", name);
+}
+
+fn drop_empty() {
+    drop(LCRQ::<u64>::new());
+}
+
+fn drop_with_queued_segments() {
+    let lcrq = LCRQ::new();
+    for i in 0..RING_SIZE*10 {
+        assert!(lcrq.enqueue(i as u64).is_ok());
+    }
+}
+
+fn drop_with_retired_segments() {
+    let lcrq = LCRQ::new();
+    for i in 0..RING_SIZE*10 {
+        assert!(lcrq.enqueue(i as u64).is_ok());
+    }
+    for _ in 0..RING_SIZE*5 {
+        assert!(lcrq.dequeue().value().is_some());
+    }
+}
+
+fn drop_with_owned_values() {
+    let lcrq = LCRQ::new();
+    for i in 0..RING_SIZE*3 {
+        assert!(lcrq.enqueue(vec![i; 10]).is_ok());
+    }
+    for _ in 0..RING_SIZE {
+        assert!(lcrq.dequeue().value().is_some());
+    }
+}
+
+fn drop_closed() {
+    let lcrq = LCRQ::new();
+    for i in 0..RING_SIZE*3 {
+        assert!(lcrq.enqueue(i as u64).is_ok());
+    }
+    lcrq.close();
+    assert!(lcrq.enqueue(0).is_err());
+    for _ in 0..RING_SIZE {
+        assert!(lcrq.dequeue().value().is_some());
+    }
+}
+
+fn drop_after_multithreaded_use() {
+    let lcrq = Arc::new(LCRQ::new());
+
+    let producers = (0..4).map(|_| {
+        let queue = lcrq.clone();
+        spawn(move || {
+            for i in 0..RING_SIZE*20 {
+                assert!(queue.enqueue(i as u64).is_ok());
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let consumers = (0..2).map(|_| {
+        let queue = lcrq.clone();
+        spawn(move || {
+            for _ in 0..RING_SIZE*20 {
+                loop {
+                    if queue.dequeue().value().is_some() {
+                        break;
+                    }
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for thread in producers.into_iter().chain(consumers) {
+        assert!(thread.join().is_ok());
+    }
+}
+
+/// Use a queue once, so state that lives as long as the thread, like the counters of the
+/// `stats` feature, exists before anything is measured
+fn warm_up() {
+    let lcrq = LCRQ::<u64>::new();
+    assert!(lcrq.enqueue(1).is_ok());
+    while lcrq.dequeue().value().is_some() {}
+}
+
+fn main() {
+    warm_up();
+    assert_no_leaks("drop_empty", drop_empty);
+    assert_no_leaks("drop_with_queued_segments", drop_with_queued_segments);
+    assert_no_leaks("drop_with_retired_segments", drop_with_retired_segments);
+    assert_no_leaks("drop_with_owned_values", drop_with_owned_values);
+    assert_no_leaks("drop_closed", drop_closed);
+    assert_no_leaks("drop_after_multithreaded_use", drop_after_multithreaded_use);
+}